@@ -1,147 +1,303 @@
-/// This file is used to execute the various actions sent to the server
+//! This file is used to execute the various actions sent to the server
 
 use std::error::Error;
-use serde::{Deserialize, Serialize};
+use std::mem;
+use std::str::FromStr;
 use strum::IntoEnumIterator;
-use strum_macros::{EnumIter, EnumString, Display};
 use read_input::prelude::*;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::{Date, Duration};
+use zeroize::Zeroizing;
 
+use crate::config::Profile;
 use crate::connection::Connection;
+use crate::menu::Action;
+use crate::password;
+use crate::output::{self, AuditEvent, AuditPage, Done, History, Revoked, Sessions, Snapshot, Users, Version};
+use lab3_protocol::{Event, Outcome, ProtocolError, Query, Reply, Request, UserRole};
 
-type EmptyResult = Result<(), String>;
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct UserInfo {
-    username: String,
-    phone_number: String,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, Display, EnumString, EnumIter)]
-enum UserRole {
-    #[strum(serialize = "StandardUser")]
-    StandardUser,
-    #[strum(serialize = "HR")]
-    HR,
-}
-
-#[derive(Serialize, Deserialize, Display, EnumString, EnumIter)]
-pub enum Action {
-    #[strum(serialize = "Show users", serialize = "1")]
-    ShowUsers,
-    #[strum(serialize = "Change my phone number", serialize = "2")]
-    ChangeOwnPhone,
-    #[strum(serialize = "Change someone's phone number", serialize = "3")]
-    ChangePhone,
-    #[strum(serialize = "Add user", serialize = "4")]
-    AddUser,
-    #[strum(serialize = "Login", serialize = "5")]
-    Login,
-    #[strum(serialize = "Logout", serialize = "6")]
-    Logout,
-    #[strum(serialize = "Exit", serialize = "7")]
-    Exit,
-}
-
-impl Action {
-    pub fn display() {
-        let mut actions = Action::iter();
-        for i in 1..=actions.len() { println!("{}.\t{}", i, actions.next().unwrap()); }
-    }
+/// Result of an action: the refusal of the server if any. An `Err` at the outer level is a
+/// failure of the connection.
+pub type ActionResult = Result<Result<(), ProtocolError>, Box<dyn Error>>;
 
-    pub fn perform(&self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        connection.send(self)?;
+const UNEXPECTED_REPLY: &str = "Unexpected reply from the server";
 
-        let res = match self {
-            Action::ShowUsers => Action::show_users(connection),
-            Action::ChangeOwnPhone => Action::change_own_phone(connection),
-            Action::ChangePhone => Action::change_phone(connection),
-            Action::AddUser => Action::add_user(connection),
-            Action::Login => Action::login(connection),
-            Action::Logout => Action::logout(connection),
-            Action::Exit => Err("Client disconnected")?
-        };
+const DAY_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]");
+const AUDIT_PAGE_SIZE: u32 = 20;
 
-        res
-    }
+pub fn display() {
+    let mut actions = Action::iter();
+    for i in 1..=actions.len() { output::note(&format!("{}.\t{}", i, actions.next().unwrap())); }
+}
 
-    pub fn show_users(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let res: Result<Vec<UserInfo>, String> = connection.receive()?;
-        match res {
-            Ok(users) => {
-                for u in users {
-                    println!("{} - {}", u.username, u.phone_number);
-                }
-            }
-            Err(e) => {println!("Error while showing users: {}", e)}
+/// Ask the inputs of an action and perform it
+pub fn perform(action: &Action, connection: &mut Connection, profile: &Profile) -> ActionResult {
+    match action {
+        Action::ShowUsers => show_users(connection),
+        Action::ChangeOwnPhone => {
+            let phone = output::ask::<String>().msg("Please enter your new phone number [0xxxxxxxxx]: ").get();
+            change_own_phone(connection, phone)
+        }
+        Action::ChangePhone => {
+            let username = output::ask::<String>().msg("Please enter the username: ").get();
+            let phone = output::ask::<String>().msg("Please enter the new phone number [0xxxxxxxxx]: ").get();
+            change_phone(connection, username, phone)
+        }
+        Action::AddUser => {
+            let username = output::ask::<String>().msg("Please enter the username: ").get();
+            let password = password::read_new("Please enter the password: ")?;
+            let phone = output::ask::<String>().msg("Please enter the phone number [0xxxxxxxxx]: ").get();
+            let role = output::ask::<UserRole>().msg("Please enter the role (Admin/Auditor/HR/StandardUser): ").get();
+            add_user(connection, username, password, phone, role)
+        }
+        Action::Login => {
+            // The username of the profile is proposed
+            let username = match &profile.username {
+                Some(default) => output::ask::<String>().msg(format!("Please enter the username [{}]: ", default)).default(default.clone()).get(),
+                None => output::ask::<String>().msg("Please enter the username: ").get(),
+            };
+            let password = password::read("Please enter the password: ")?;
+            login(connection, username, password)
+        }
+        Action::Logout => logout(connection),
+        Action::Backup => backup(connection),
+        Action::Restore => {
+            let snapshot = output::ask::<String>().msg("Please enter the snapshot name: ").get();
+            restore(connection, snapshot)
         }
+        Action::ShowHistory => {
+            let username = output::ask::<String>().msg("Please enter the username: ").get();
+            show_history(connection, username)
+        }
+        Action::RevertPhone => {
+            let username = output::ask::<String>().msg("Please enter the username: ").get();
+            let version = output::ask::<u32>().msg("Please enter the version to revert to: ").get();
+            revert_phone(connection, username, version)
+        }
+        Action::QueryAudit => {
+            output::note("Leave a filter empty to ignore it");
+            let filter = AuditFilter {
+                actor: optional_input::<String>("Actor username: "),
+                target: optional_input::<String>("Target username: "),
+                event: optional_input::<Event>("Action (Login, ChangePhone, AddUser, ...): "),
+                outcome: optional_input::<Outcome>("Outcome (Success/Failure/Denied): "),
+                from: optional_input::<Day>("From day [yyyy-mm-dd]: "),
+                to: optional_input::<Day>("To day, included [yyyy-mm-dd]: "),
+                page: output::ask::<u32>().msg("Page [1]: ").add_test(|p| *p > 0).default(1).get(),
+            };
+            query_audit(connection, filter)
+        }
+        Action::RevokeSessions => {
+            let username = output::ask::<String>().msg("Please enter the username: ").get();
+            revoke_sessions(connection, username)
+        }
+        Action::ShowSessions => show_sessions(connection),
+        Action::TerminateSession => {
+            let id = output::ask::<u64>().msg("Please enter the session id: ").get();
+            terminate_session(connection, id)
+        }
+        Action::Exit => {
+            connection.close()?;
+            Ok(Ok(()))
+        }
+    }
+}
 
-        Ok(())
+pub fn show_users(connection: &mut Connection) -> ActionResult {
+    match connection.request(Request::ShowUsers)? {
+        Ok(Reply::Users(users)) => {
+            output::show(&Users::from(users))?;
+            Ok(Ok(()))
+        }
+        Ok(_) => Err(UNEXPECTED_REPLY)?,
+        Err(e) => Ok(Err(report("Error while showing users", e))),
     }
+}
 
-    pub fn change_own_phone(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let phone_number = input::<String>().msg("Please enter your new phone number [0xxxxxxxxx]: ").get();
-        connection.send(&phone_number)?;
+pub fn change_own_phone(connection: &mut Connection, phone: String) -> ActionResult {
+    let res = connection.request(Request::ChangeOwnPhone { phone })?;
+    done(res, "Error while changing own phone")
+}
 
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("Error while changing own phone: {}", e);
-        }
+pub fn change_phone(connection: &mut Connection, username: String, phone: String) -> ActionResult {
+    let res = connection.request(Request::ChangePhone { username, phone })?;
+    done(res, "Error while changing phone")
+}
 
-        Ok(())
-    }
+/// The password is moved into the request, which erases it once sent
+pub fn add_user(connection: &mut Connection, username: String, mut password: Zeroizing<String>, phone: String, role: UserRole) -> ActionResult {
+    let res = connection.request(Request::AddUser { username, password: mem::take(&mut password), phone, role })?;
+    done(res, "Error while adding user")
+}
 
-    pub fn change_phone(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let username = input::<String>().msg("Please enter the username: ").get();
-        let phone_number = input::<String>().msg("Please enter the new phone number [0xxxxxxxxx]: ").get();
-        connection.send(&username)?;
-        connection.send(&phone_number)?;
+/// The password is moved into the request, which erases it once sent
+pub fn login(connection: &mut Connection, username: String, mut password: Zeroizing<String>) -> ActionResult {
+    match connection.request(Request::Login { username, password: mem::take(&mut password) })? {
+        Ok(Reply::Session(token)) => {
+            connection.set_token(Some(token));
+            Ok(Ok(()))
+        }
+        res => done(res, "Error during login"),
+    }
+}
 
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("Error while changing phone: {}", e);
+pub fn logout(connection: &mut Connection) -> ActionResult {
+    match connection.request(Request::Logout)? {
+        Ok(Reply::Done) => {
+            connection.set_token(None);
+            Ok(Ok(()))
         }
+        Ok(_) => Err(UNEXPECTED_REPLY)?,
+        Err(e) => Ok(Err(report("Error during logout", e))),
+    }
+}
 
-        Ok(())
+pub fn backup(connection: &mut Connection) -> ActionResult {
+    match connection.request(Request::Backup)? {
+        Ok(Reply::Snapshot(snapshot)) => {
+            output::show(&Snapshot { snapshot })?;
+            Ok(Ok(()))
+        }
+        Ok(_) => Err(UNEXPECTED_REPLY)?,
+        Err(e) => Ok(Err(report("Error while creating snapshot", e))),
     }
+}
 
-    pub fn add_user(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let username = input::<String>().msg("Please enter the username: ").get();
-        let password = input::<String>().msg("Please enter the password: ").get();
-        let phone_number = input::<String>().msg("Please enter the phone number [0xxxxxxxxx]: ").get();
-        let role = input::<UserRole>().msg("Please enter the role (HR/StandardUser): ").get();
-        connection.send(&username)?;
-        connection.send(&password)?;
-        connection.send(&phone_number)?;
-        connection.send(&role)?;
+pub fn restore(connection: &mut Connection, snapshot: String) -> ActionResult {
+    let res = connection.request(Request::Restore { snapshot })?;
+    done(res, "Error while restoring snapshot")
+}
 
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("Error while adding user: {}", e);
+pub fn show_history(connection: &mut Connection, username: String) -> ActionResult {
+    match connection.request(Request::ShowHistory { username: username.clone() })? {
+        Ok(Reply::History(history)) => {
+            let versions = history.into_iter().zip(1..).map(|(c, version)| Version {
+                version,
+                actor: c.actor,
+                field: c.field.to_string(),
+                old_value: c.old_value,
+                new_value: c.new_value,
+                timestamp: c.timestamp,
+            }).collect();
+            output::show(&History { username, versions })?;
+            Ok(Ok(()))
         }
-
-        Ok(())
+        Ok(_) => Err(UNEXPECTED_REPLY)?,
+        Err(e) => Ok(Err(report("Error while showing history", e))),
     }
+}
+
+pub fn revert_phone(connection: &mut Connection, username: String, version: u32) -> ActionResult {
+    let res = connection.request(Request::RevertPhone { username, version })?;
+    done(res, "Error while reverting phone")
+}
+
+/// Filters of an audit query, the days are included and the pages start at 1
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub event: Option<Event>,
+    pub outcome: Option<Outcome>,
+    pub from: Option<Day>,
+    pub to: Option<Day>,
+    pub page: u32,
+}
 
-    pub fn login(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let username = input::<String>().msg("Please enter the username: ").get();
-        let password = input::<String>().msg("Please enter the password: ").get();
-        connection.send(&username)?;
-        connection.send(&password)?;
+pub fn query_audit(connection: &mut Connection, filter: AuditFilter) -> ActionResult {
+    let query = Query {
+        actor: filter.actor,
+        target: filter.target,
+        event: filter.event,
+        outcome: filter.outcome,
+        from: filter.from.map(|d| d.0.midnight().assume_utc().unix_timestamp()),
+        to: filter.to.map(|d| (d.0.midnight().assume_utc() + Duration::days(1)).unix_timestamp()),
+        page: filter.page - 1,
+        page_size: AUDIT_PAGE_SIZE,
+    };
 
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("Error during login: {}", e);
+    match connection.request(Request::QueryAudit { query })? {
+        Ok(Reply::AuditPage(p)) => {
+            let events = p.events.into_iter().map(|e| AuditEvent {
+                seq: e.seq,
+                timestamp: e.timestamp,
+                actor: e.actor,
+                event: e.event.to_string(),
+                target: e.target,
+                outcome: e.outcome.to_string(),
+            }).collect();
+            let pages = p.total.div_ceil(AUDIT_PAGE_SIZE as u64).max(1);
+            output::show(&AuditPage { page: filter.page, pages, total: p.total, events })?;
+            Ok(Ok(()))
         }
+        Ok(_) => Err(UNEXPECTED_REPLY)?,
+        Err(e) => Ok(Err(report("Error while querying audit log", e))),
+    }
+}
 
-        Ok(())
+pub fn revoke_sessions(connection: &mut Connection, username: String) -> ActionResult {
+    match connection.request(Request::RevokeSessions { username })? {
+        Ok(Reply::Revoked(revoked)) => {
+            output::show(&Revoked { revoked })?;
+            Ok(Ok(()))
+        }
+        Ok(_) => Err(UNEXPECTED_REPLY)?,
+        Err(e) => Ok(Err(report("Error while revoking sessions", e))),
     }
+}
 
-    pub fn logout(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        let res = connection.receive::<EmptyResult>()?;
-        if let Err(e) = res {
-            println!("{}", e);
+pub fn show_sessions(connection: &mut Connection) -> ActionResult {
+    match connection.request(Request::ListSessions)? {
+        Ok(Reply::Sessions(sessions)) => {
+            output::show(&Sessions::from(sessions))?;
+            Ok(Ok(()))
         }
+        Ok(_) => Err(UNEXPECTED_REPLY)?,
+        Err(e) => Ok(Err(report("Error while listing sessions", e))),
+    }
+}
+
+pub fn terminate_session(connection: &mut Connection, id: u64) -> ActionResult {
+    let res = connection.request(Request::TerminateSession { id })?;
+    done(res, "Error while terminating session")
+}
+
+/// Handle the response of an action which has nothing to return
+fn done(res: Result<Reply, ProtocolError>, context: &str) -> ActionResult {
+    match res {
+        Ok(Reply::Done) => {
+            output::show(&Done::default())?;
+            Ok(Ok(()))
+        }
+        Ok(_) => Err(UNEXPECTED_REPLY)?,
+        Err(e) => Ok(Err(report(context, e))),
+    }
+}
+
+/// Print a refusal of the server and return it
+fn report(context: &str, e: ProtocolError) -> ProtocolError {
+    output::refusal(context, &e);
+    e
+}
+
+/// Ask for a value that can be left empty
+fn optional_input<T: FromStr + 'static>(msg: &str) -> Option<T> {
+    let value = output::ask::<String>()
+        .msg(msg)
+        .add_test(|s| s.is_empty() || s.parse::<T>().is_ok())
+        .get();
+
+    if value.is_empty() { None } else { value.parse::<T>().ok() }
+}
+
+/// A day entered as yyyy-mm-dd
+#[derive(Clone, Debug)]
+pub struct Day(Date);
+
+impl FromStr for Day {
+    type Err = time::error::Parse;
 
-        Ok(())
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Day(Date::parse(s, DAY_FORMAT)?))
     }
 }
@@ -0,0 +1,326 @@
+//! This file is used to run a single action given on the command line, without the menu.
+//!
+//! The client logs in first when a password is given, with `--password`, `CLIENT_PASSWORD` or
+//! the first line of the standard input with `--password-stdin`. The result is printed on the
+//! standard output, the refusals on the standard error, both in the format chosen with
+//! `--output`, and the exit code tells why the action was refused (see `error::exit_code`).
+
+use crate::action::{self, ActionResult, AuditFilter, Day};
+use crate::config::{Profile, ProfileArgs};
+use crate::connection::Connection;
+use crate::error::{self, EXIT_CONNECTION, EXIT_USAGE};
+use crate::menu::Action;
+use crate::output::{self, Format};
+use crate::password;
+use crate::tls::TlsConnector;
+use clap::{Args, Parser, Subcommand};
+use lab3_protocol::{Event, Outcome, ProtocolError, UserRole};
+use std::convert::Infallible;
+use std::env;
+use std::io;
+use zeroize::Zeroizing;
+
+#[derive(Parser, Debug)]
+#[command(about = "Client of the HR online user directory, the menu is shown without a command")]
+pub struct Cli {
+    #[command(flatten)]
+    pub profile: ProfileArgs,
+
+    #[command(flatten)]
+    pub credentials: Credentials,
+
+    /// Format of the results and of the errors
+    #[arg(short, long, value_enum, default_value_t = Format::Table, global = true)]
+    pub output: Format,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Password of the login, the username comes from the profile
+#[derive(Args, Debug)]
+pub struct Credentials {
+    /// Password of the login, visible to the other users of the machine: prefer the other ways
+    #[arg(long, env = "CLIENT_PASSWORD", hide_env_values = true, value_parser = secret)]
+    password: Option<Zeroizing<String>>,
+    /// Read the password of the login from the first line of the standard input, or without
+    /// echo in a terminal
+    #[arg(long, conflicts_with = "password")]
+    password_stdin: bool,
+}
+
+// Keep a password given as argument in a buffer erased once dropped
+fn secret(value: &str) -> Result<Zeroizing<String>, Infallible> {
+    Ok(Zeroizing::new(value.to_string()))
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Show and add users
+    #[command(subcommand, visible_alias = "user")]
+    Users(UsersCommand),
+    /// Change and revert phone numbers
+    #[command(subcommand)]
+    Phone(PhoneCommand),
+    /// Snapshots of the database
+    #[command(subcommand)]
+    Backup(BackupCommand),
+    /// Query the audit log
+    Audit(AuditArgs),
+    /// Connected sessions
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UsersCommand {
+    /// List the users and their phone number
+    List,
+    /// Add a user, its password is read from NEW_USER_PASSWORD or the standard input, twice in
+    /// a terminal
+    Add {
+        username: String,
+        #[arg(long)]
+        phone: String,
+        /// Admin, Auditor, HR or StandardUser
+        #[arg(long)]
+        role: UserRole,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PhoneCommand {
+    /// Change the phone number of a user
+    Set { username: String, phone: String },
+    /// Change the phone number of the logged in user
+    SetOwn { phone: String },
+    /// Show the changes of the phone number of a user
+    History { username: String },
+    /// Revert the phone number of a user to a version of its history
+    Revert { username: String, version: u32 },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum BackupCommand {
+    /// Create a snapshot of the database and print its name
+    Create,
+    /// Replace the database by a snapshot
+    Restore { snapshot: String },
+}
+
+#[derive(Args, Debug)]
+pub struct AuditArgs {
+    #[arg(long)]
+    actor: Option<String>,
+    #[arg(long)]
+    target: Option<String>,
+    /// Action, for example Login, ChangePhone or AddUser
+    #[arg(long)]
+    event: Option<Event>,
+    /// Success, Failure or Denied
+    #[arg(long)]
+    outcome: Option<Outcome>,
+    /// First day, yyyy-mm-dd
+    #[arg(long)]
+    from: Option<Day>,
+    /// Last day included, yyyy-mm-dd
+    #[arg(long)]
+    to: Option<Day>,
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    page: u32,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionsCommand {
+    /// List the connected sessions
+    List,
+    /// Revoke every session of a user
+    Revoke { username: String },
+    /// Close a connected session
+    Terminate { id: u64 },
+}
+
+impl Command {
+    /// Menu action performing this command
+    fn action(&self) -> Action {
+        match self {
+            Command::Users(UsersCommand::List) => Action::ShowUsers,
+            Command::Users(UsersCommand::Add { .. }) => Action::AddUser,
+            Command::Phone(PhoneCommand::Set { .. }) => Action::ChangePhone,
+            Command::Phone(PhoneCommand::SetOwn { .. }) => Action::ChangeOwnPhone,
+            Command::Phone(PhoneCommand::History { .. }) => Action::ShowHistory,
+            Command::Phone(PhoneCommand::Revert { .. }) => Action::RevertPhone,
+            Command::Backup(BackupCommand::Create) => Action::Backup,
+            Command::Backup(BackupCommand::Restore { .. }) => Action::Restore,
+            Command::Audit(_) => Action::QueryAudit,
+            Command::Sessions(SessionsCommand::List) => Action::ShowSessions,
+            Command::Sessions(SessionsCommand::Revoke { .. }) => Action::RevokeSessions,
+            Command::Sessions(SessionsCommand::Terminate { .. }) => Action::TerminateSession,
+        }
+    }
+}
+
+/// Run a command and return the exit code of the client
+pub fn run(command: Command, credentials: Credentials, profile: &Profile, connector: &TlsConnector) -> i32 {
+    // Every input is read before connecting, a missing one is a usage error
+    let inputs = match Inputs::read(credentials, &command, profile) {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            output::failure("usage", &e, EXIT_USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let result = crate::connect(profile, connector).and_then(|mut conn| {
+        let res = execute(&mut conn, command, inputs)?;
+        // The server waits for the next request after its banner
+        conn.banner()?;
+        // Exiting closes the session on the server, a command never resumes it
+        conn.close()?;
+        Ok(res)
+    });
+    match result {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => error::exit_code(&e),
+        Err(e) => {
+            output::failure("connection", &e.to_string(), EXIT_CONNECTION);
+            EXIT_CONNECTION
+        }
+    }
+}
+
+/// Inputs of a command which are not given as arguments, the passwords are erased from memory
+/// once sent
+struct Inputs {
+    login: Option<(String, Zeroizing<String>)>,
+    new_password: Option<Zeroizing<String>>,
+}
+
+impl Inputs {
+    fn read(credentials: Credentials, command: &Command, profile: &Profile) -> Result<Inputs, String> {
+        let missing = |what: &'static str| move |e: io::Error| match e.kind() {
+            io::ErrorKind::InvalidData => format!("The {} is too long", what),
+            _ => format!("The {} must be given on the standard input", what),
+        };
+
+        let password = match credentials.password {
+            Some(password) => Some(password),
+            None if credentials.password_stdin => Some(password::read("Password: ").map_err(missing("password"))?),
+            None => None,
+        };
+        let login = match (password, &profile.username) {
+            (Some(password), Some(username)) => Some((username.clone(), password)),
+            (Some(_), None) => Err("A username is required to log in, with --username, CLIENT_USERNAME or the profile")?,
+            (None, _) => None,
+        };
+
+        let new_password = match command {
+            Command::Users(UsersCommand::Add { .. }) => match env::var("NEW_USER_PASSWORD") {
+                Ok(password) => Some(Zeroizing::new(password)),
+                Err(_) => Some(password::read_new("Password of the new user: ").map_err(missing("password of the new user"))?),
+            },
+            _ => None,
+        };
+        Ok(Inputs { login, new_password })
+    }
+}
+
+// Log in if asked, then perform the command
+fn execute(conn: &mut Connection, command: Command, inputs: Inputs) -> ActionResult {
+    if let Some((username, password)) = inputs.login {
+        conn.banner()?;
+        if let Err(e) = action::login(conn, username, password)? {
+            return Ok(Err(e));
+        }
+    }
+
+    conn.banner()?;
+    if command.action().capability().is_some_and(|c| !conn.supports(c)) {
+        output::refusal("Cannot run the command", &ProtocolError::Unsupported);
+        return Ok(Err(ProtocolError::Unsupported));
+    }
+
+    match command {
+        Command::Users(UsersCommand::List) => action::show_users(conn),
+        Command::Users(UsersCommand::Add { username, phone, role }) => {
+            action::add_user(conn, username, inputs.new_password.unwrap_or_default(), phone, role)
+        }
+        Command::Phone(PhoneCommand::Set { username, phone }) => action::change_phone(conn, username, phone),
+        Command::Phone(PhoneCommand::SetOwn { phone }) => action::change_own_phone(conn, phone),
+        Command::Phone(PhoneCommand::History { username }) => action::show_history(conn, username),
+        Command::Phone(PhoneCommand::Revert { username, version }) => action::revert_phone(conn, username, version),
+        Command::Backup(BackupCommand::Create) => action::backup(conn),
+        Command::Backup(BackupCommand::Restore { snapshot }) => action::restore(conn, snapshot),
+        Command::Audit(a) => action::query_audit(conn, AuditFilter {
+            actor: a.actor,
+            target: a.target,
+            event: a.event,
+            outcome: a.outcome,
+            from: a.from,
+            to: a.to,
+            page: a.page,
+        }),
+        Command::Sessions(SessionsCommand::List) => action::show_sessions(conn),
+        Command::Sessions(SessionsCommand::Revoke { username }) => action::revoke_sessions(conn, username),
+        Command::Sessions(SessionsCommand::Terminate { id }) => action::terminate_session(conn, id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from([&["lab3_client"], args].concat())
+    }
+
+    fn action(args: &[&str]) -> Action {
+        parse(args).unwrap().command.unwrap().action()
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert!(parse(&[]).unwrap().command.is_none());
+        assert_eq!(action(&["users", "list"]), Action::ShowUsers);
+        assert_eq!(action(&["user", "add", "alice", "--phone", "0791234567", "--role", "HR"]), Action::AddUser);
+        assert_eq!(action(&["phone", "set", "alice", "0791234567"]), Action::ChangePhone);
+        assert_eq!(action(&["phone", "set-own", "0791234567"]), Action::ChangeOwnPhone);
+        assert_eq!(action(&["phone", "history", "alice"]), Action::ShowHistory);
+        assert_eq!(action(&["phone", "revert", "alice", "2"]), Action::RevertPhone);
+        assert_eq!(action(&["backup", "create"]), Action::Backup);
+        assert_eq!(action(&["backup", "restore", "db_1.ron"]), Action::Restore);
+        assert_eq!(action(&["audit"]), Action::QueryAudit);
+        assert_eq!(action(&["sessions", "list"]), Action::ShowSessions);
+        assert_eq!(action(&["sessions", "revoke", "alice"]), Action::RevokeSessions);
+        assert_eq!(action(&["sessions", "terminate", "3"]), Action::TerminateSession);
+    }
+
+    #[test]
+    fn arguments_are_checked() {
+        let cli = parse(&["users", "add", "alice", "--phone", "0791234567", "--role", "Admin", "-o", "json"]).unwrap();
+        assert!(matches!(cli.output, Format::Json));
+        match cli.command {
+            Some(Command::Users(UsersCommand::Add { username, role, .. })) => assert_eq!((username.as_str(), role), ("alice", UserRole::Admin)),
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        let cli = parse(&["audit", "--event", "Login", "--outcome", "Denied", "--from", "2024-01-31", "--page", "2"]).unwrap();
+        match cli.command {
+            Some(Command::Audit(a)) => {
+                assert_eq!((a.event, a.outcome, a.page), (Some(Event::Login), Some(Outcome::Denied), 2));
+                assert!(a.from.is_some() && a.to.is_none());
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        let kind = |args: &[&str]| parse(args).unwrap_err().kind();
+        assert_eq!(kind(&["users", "add", "alice", "--phone", "0791234567", "--role", "Boss"]), ErrorKind::ValueValidation);
+        assert_eq!(kind(&["users", "add", "alice", "--role", "HR"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(kind(&["audit", "--page", "0"]), ErrorKind::ValueValidation);
+        assert_eq!(kind(&["audit", "--from", "31.01.2024"]), ErrorKind::ValueValidation);
+        assert_eq!(kind(&["phone", "revert", "alice", "last"]), ErrorKind::ValueValidation);
+        assert_eq!(kind(&["--password", "x", "--password-stdin", "users", "list"]), ErrorKind::ArgumentConflict);
+        assert_eq!(kind(&["users", "remove", "alice"]), ErrorKind::InvalidSubcommand);
+    }
+}
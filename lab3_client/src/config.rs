@@ -0,0 +1,238 @@
+//! This file is used to choose the server the client connects to.
+//!
+//! The servers are described by named profiles in a per-user TOML file, for example:
+//!
+//! ```toml
+//! default_profile = "dev"
+//!
+//! [profiles.dev]
+//! host = "localhost"
+//! ca = "./tls/root/ec_cert.pem"
+//!
+//! [profiles.prod]
+//! host = "hr.example.com"
+//! port = 4444
+//! pins = ["<current key>", "<backup key>"]
+//! username = "alice"
+//! ```
+//!
+//! A setting of the profile is overridden by its environment variable, itself overridden by its
+//! command-line argument. A relative `ca` of a profile is relative to the directory of the file.
+
+use crate::pin::Pin;
+use clap::Args;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_HOST: &str = "localhost";
+const DEFAULT_PORT: u16 = 4444;
+const DEFAULT_CA_PATH: &str = "./tls/root/ec_cert.pem";
+
+/// Arguments selecting the server, they override the profile
+#[derive(Args, Debug)]
+pub struct ProfileArgs {
+    /// Profile of the configuration file to use, instead of its default profile
+    #[arg(short, long, env = "CLIENT_PROFILE")]
+    profile: Option<String>,
+    /// Configuration file, ~/.config/lab3_client/config.toml by default
+    #[arg(long, env = "CLIENT_CONFIG")]
+    config: Option<PathBuf>,
+    /// Name of the server, it must match its certificate
+    #[arg(long, env = "SERVER_HOST")]
+    host: Option<String>,
+    #[arg(long, env = "SERVER_PORT")]
+    port: Option<u16>,
+    /// PEM root certificate of the server
+    #[arg(long, env = "SERVER_CA")]
+    ca: Option<String>,
+    /// SHA-256 fingerprint of an accepted server key, can be repeated or comma separated
+    #[arg(long = "pin", env = "SERVER_PINS", value_delimiter = ',')]
+    pins: Vec<Pin>,
+    /// Username of the login, proposed by the interactive login
+    #[arg(short, long, env = "CLIENT_USERNAME")]
+    username: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    default_profile: Option<String>,
+    profiles: BTreeMap<String, ProfileConfig>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ProfileConfig {
+    host: Option<String>,
+    port: Option<u16>,
+    ca: Option<String>,
+    pins: Vec<String>,
+    username: Option<String>,
+}
+
+/// Server to connect to and how to trust it
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub host: String,
+    pub port: u16,
+    /// Root certificate, not needed when the server key is pinned
+    pub ca_file: Option<String>,
+    pub pins: Vec<Pin>,
+    pub username: Option<String>,
+}
+
+impl Profile {
+    /// Resolve the profile selected on the command line, in the environment or by default
+    pub fn load(cli: &ProfileArgs) -> Result<Profile, String> {
+        let (path, explicit) = match &cli.config {
+            Some(path) => (Some(path.clone()), true),
+            None => (default_config_path(), false),
+        };
+        let file = match &path {
+            Some(path) if explicit || path.exists() => read(path)?,
+            _ => ConfigFile::default(),
+        };
+
+        let name = cli.profile.clone().or(file.default_profile.clone());
+        let profile = match &name {
+            Some(name) => match file.profiles.get(name) {
+                Some(profile) => profile,
+                None if file.profiles.is_empty() => Err(format!("unknown profile \"{}\", no profile is configured", name))?,
+                None => Err(format!(
+                    "unknown profile \"{}\", the configured profiles are: {}",
+                    name, file.profiles.keys().cloned().collect::<Vec<_>>().join(", ")))?,
+            },
+            None => &ProfileConfig::default(),
+        };
+
+        let pins = if cli.pins.is_empty() {
+            profile.pins.iter()
+                .map(|p| p.parse().map_err(|e| format!("profile \"{}\": {}", name.as_deref().unwrap_or_default(), e)))
+                .collect::<Result<Vec<Pin>, String>>()?
+        } else {
+            cli.pins.clone()
+        };
+        // The default root certificate is only used when the key of the server is not pinned
+        let base = path.as_deref().and_then(Path::parent).unwrap_or(Path::new(""));
+        let ca_file = cli.ca.clone().or(profile.ca.as_ref().map(|ca| base.join(ca).to_string_lossy().into_owned()))
+            .or_else(|| pins.is_empty().then(|| DEFAULT_CA_PATH.to_string()));
+
+        Ok(Profile {
+            host: cli.host.clone().or(profile.host.clone()).unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            port: cli.port.or(profile.port).unwrap_or(DEFAULT_PORT),
+            ca_file,
+            pins,
+            username: cli.username.clone().or(profile.username.clone()),
+        })
+    }
+}
+
+fn read(path: &PathBuf) -> Result<ConfigFile, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read the configuration file \"{}\": {}", path.display(), e))?;
+    toml::from_str(&content).map_err(|e| format!("invalid configuration file \"{}\": {}", path.display(), e))
+}
+
+/// `$XDG_CONFIG_HOME/lab3_client/config.toml`, or in `~/.config` if it is not set
+fn default_config_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("lab3_client").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches, Parser};
+    use std::ffi::OsStr;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        profile: ProfileArgs,
+    }
+
+    const CONFIG: &str = r#"
+default_profile = "dev"
+
+[profiles.dev]
+host = "dev.example.com"
+port = 5555
+ca = "certs/ca.pem"
+username = "alice"
+
+[profiles.prod]
+host = "prod.example.com"
+ca = "/etc/lab3/ca.pem"
+"#;
+
+    /// Write `CONFIG` in a temporary directory, returns the path of the file
+    fn config_file(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lab3_client_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, CONFIG).unwrap();
+        path
+    }
+
+    /// Resolve the profile with the variables of `env` instead of the environment of the process.
+    /// A variable is given to clap as the default value of its argument, which the argument
+    /// overrides like the variable.
+    fn load_with(args: &[&str], env: &[(&str, &'static str)]) -> Result<Profile, String> {
+        let command = Cli::command().mut_args(|arg| {
+            let value = arg.get_env().and_then(|name| env.iter().find(|(n, _)| name == OsStr::new(n))).map(|(_, v)| *v);
+            arg.env(None).default_value(value)
+        });
+        let matches = command.try_get_matches_from([&["lab3_client"], args].concat()).unwrap();
+        Profile::load(&Cli::from_arg_matches(&matches).unwrap().profile)
+    }
+
+    fn load(args: &[&str]) -> Result<Profile, String> {
+        load_with(args, &[])
+    }
+
+    #[test]
+    fn precedence() {
+        let path = config_file("precedence");
+        let config = path.to_str().unwrap();
+
+        let profile = load(&["--config", config]).unwrap();
+        assert_eq!((profile.host.as_str(), profile.port), ("dev.example.com", 5555));
+        assert_eq!(profile.username.as_deref(), Some("alice"));
+
+        let env = [("SERVER_HOST", "env.example.com")];
+        let from_env = load_with(&["--config", config], &env).unwrap();
+        assert_eq!((from_env.host.as_str(), from_env.port), ("env.example.com", 5555));
+        let from_cli = load_with(&["--config", config, "--host", "cli.example.com"], &env).unwrap();
+        assert_eq!(from_cli.host, "cli.example.com");
+
+        let prod = load(&["--config", config, "--profile", "prod", "--port", "4445"]).unwrap();
+        assert_eq!((prod.host.as_str(), prod.port), ("prod.example.com", 4445));
+        assert_eq!(prod.username, None);
+
+        assert!(load(&["--config", config, "--profile", "test"]).unwrap_err().contains("dev, prod"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn ca_is_relative_to_the_file() {
+        let path = config_file("ca");
+        let config = path.to_str().unwrap();
+
+        let dev = load(&["--config", config]).unwrap();
+        assert_eq!(dev.ca_file.map(PathBuf::from), Some(path.parent().unwrap().join("certs/ca.pem")));
+
+        let prod = load(&["--config", config, "--profile", "prod"]).unwrap();
+        assert_eq!(prod.ca_file.as_deref(), Some("/etc/lab3/ca.pem"));
+
+        // A path given on the command line stays relative to the working directory
+        let cli = load(&["--config", config, "--ca", "ca.pem"]).unwrap();
+        assert_eq!(cli.ca_file.as_deref(), Some("ca.pem"));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
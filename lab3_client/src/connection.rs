@@ -1,29 +1,126 @@
-use native_tls::TlsStream;
+//! This file is used to exchange messages with the server, using the framing of the protocol
+
+use lab3_protocol::frame::{deserialize, read_frame, serialize, write_frame};
+use crate::error::Closed;
+use lab3_protocol::{Capability, ProtocolError, Reply, Request, RequestMessage, ServerMessage};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::error::Error;
-use std::net::TcpStream;
+use std::io::{Read, Write};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Encrypted stream to the server, whatever the TLS backend
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
 
 pub struct Connection {
-    stream: TlsStream<TcpStream>,
+    stream: Box<dyn Stream>,
+    next_id: u64,
+    /// Capabilities negotiated during the hello
+    capabilities: Vec<Capability>,
+    /// Token of the session opened by the login, used to resume it after losing the connection
+    token: Option<String>,
 }
 
 impl Connection {
-    pub fn new(stream: TlsStream<TcpStream>) -> Connection {
-        Connection { stream }
+    pub fn new(stream: Box<dyn Stream>) -> Connection {
+        Connection { stream, next_id: 0, capabilities: Vec::new(), token: None }
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Vec<Capability>) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
     }
 
     pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>>
         where
             T: Serialize,
     {
-        Ok(bincode::serialize_into(&mut self.stream, &o)?)
+        // The payload may carry a password or a token
+        let payload = Zeroizing::new(serialize(o)?);
+        write_frame(&mut self.stream, &payload)
     }
 
     pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>>
         where
             T: DeserializeOwned,
     {
-        Ok(bincode::deserialize_from(&mut self.stream)?)
+        let payload = Zeroizing::new(read_frame(&mut self.stream)
+            .map_err(|e| format!("Invalid frame received from the server: {}", e))?);
+
+        match deserialize(&payload) {
+            Ok(o) => Ok(o),
+            Err(e) => Err(format!("Server sent a malformed message: {}", e))?,
+        }
+    }
+
+    /// Wait for the banner sent by the server before each request
+    pub fn banner(&mut self) -> Result<String, Box<dyn Error>> {
+        match self.receive_message()? {
+            ServerMessage::Banner(banner) => Ok(banner),
+            _ => Err("Server sent an unexpected message instead of the banner")?,
+        }
+    }
+
+    /// Receive a message of the server, a notice ends the connection with its explanation
+    fn receive_message(&mut self) -> Result<ServerMessage, Box<dyn Error>> {
+        match self.receive::<ServerMessage>()? {
+            ServerMessage::Notice(notice) => Err(Closed(notice))?,
+            message => Ok(message),
+        }
+    }
+
+    /// Send a request and wait for the response answering it
+    ///
+    /// # Error
+    /// If the exchange failed or the response does not answer this request.
+    pub fn request(&mut self, request: Request) -> Result<Result<Reply, ProtocolError>, Box<dyn Error>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut message = RequestMessage { id, request };
+        let sent = self.send(&message);
+        erase_password(&mut message.request);
+        if let Err(e) = sent {
+            // The server may have closed the connection right after sending a notice
+            if let Ok(ServerMessage::Notice(notice)) = self.receive::<ServerMessage>() {
+                Err(Closed(notice))?
+            }
+            return Err(e);
+        }
+
+        let response = match self.receive_message()? {
+            ServerMessage::Response(response) => response,
+            _ => Err("Server sent an unexpected message instead of a response")?,
+        };
+        if response.id != id {
+            Err(format!("Server desynchronized: expected response {}, received {}", id, response.id))?
+        }
+        Ok(response.result)
+    }
+
+    /// Tell the server that the client leaves, no response is expected
+    pub fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&RequestMessage { id, request: Request::Exit })
+    }
+}
+
+/// Erase the password of a request from memory once it is sent
+fn erase_password(request: &mut Request) {
+    if let Request::Login { password, .. } | Request::AddUser { password, .. } = request {
+        password.zeroize();
     }
 }
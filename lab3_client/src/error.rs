@@ -0,0 +1,66 @@
+//! This file is used to present the errors returned by the server to the user
+
+use lab3_protocol::{Expiry, Notice, ProtocolError, Resource};
+use std::error::Error;
+use std::fmt;
+
+/// Exit code when the client could not reach the server or the connection failed
+pub const EXIT_CONNECTION: i32 = 1;
+
+/// Exit code when the arguments of the client are invalid or an input is missing
+pub const EXIT_USAGE: i32 = 10;
+
+/// Message explaining why the server refused a request
+pub fn describe(e: &ProtocolError) -> String {
+    match e {
+        ProtocolError::Unauthenticated => "you are not logged in or your credentials are invalid".to_string(),
+        ProtocolError::Forbidden => "you are not allowed to perform this action".to_string(),
+        ProtocolError::NotFound(Resource::User) => "this user does not exist".to_string(),
+        ProtocolError::NotFound(Resource::Version) => "this version does not exist".to_string(),
+        ProtocolError::NotFound(Resource::Snapshot) => "this snapshot does not exist".to_string(),
+        ProtocolError::NotFound(Resource::Session) => "there is no open session".to_string(),
+        ProtocolError::AlreadyExists(Resource::User) => "this user already exists".to_string(),
+        ProtocolError::AlreadyExists(Resource::Session) => "you are already logged in".to_string(),
+        ProtocolError::AlreadyExists(r) => format!("this {} already exists", r.to_string().to_lowercase()),
+        ProtocolError::Validation { field, rule } => format!("invalid {}, it {}", field, rule),
+        ProtocolError::RateLimited => "too many requests, please try again later".to_string(),
+        ProtocolError::Unsupported => "this action is not supported by the server".to_string(),
+        ProtocolError::Internal => "the server failed to perform the action, please try again later".to_string(),
+    }
+}
+
+/// Error raised when the server closed the connection on purpose, with a notice
+#[derive(Debug)]
+pub struct Closed(pub Notice);
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", describe_notice(&self.0))
+    }
+}
+
+impl Error for Closed {}
+
+/// Message explaining why the server closed the connection
+pub fn describe_notice(notice: &Notice) -> String {
+    match notice {
+        Notice::SessionExpired(Expiry::Idle) => "Your session expired after being idle for too long, please reconnect".to_string(),
+        Notice::SessionExpired(Expiry::Lifetime) => "Your session reached its maximum duration, please reconnect".to_string(),
+        Notice::ShuttingDown => "The server is shutting down, please reconnect later".to_string(),
+        Notice::Terminated => "Your session was terminated by HR".to_string(),
+    }
+}
+
+/// Exit code of the client when the last action was refused with this error
+pub fn exit_code(e: &ProtocolError) -> i32 {
+    match e {
+        ProtocolError::Internal => 2,
+        ProtocolError::Unauthenticated => 3,
+        ProtocolError::Forbidden => 4,
+        ProtocolError::NotFound(_) => 5,
+        ProtocolError::AlreadyExists(_) => 6,
+        ProtocolError::Validation { .. } => 7,
+        ProtocolError::RateLimited => 8,
+        ProtocolError::Unsupported => 9,
+    }
+}
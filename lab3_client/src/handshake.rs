@@ -0,0 +1,22 @@
+//! This file is used to negotiate the protocol version and the capabilities with the server
+
+use crate::connection::Connection;
+use lab3_protocol::{Capability, ClientHello, ServerHello, PROTOCOL_VERSION};
+use std::error::Error;
+use strum::IntoEnumIterator;
+
+/// Announce the version and capabilities of the client, returns the negotiated capabilities
+///
+/// # Error
+/// If the server does not support the version of the client or the exchange failed.
+pub fn hello(conn: &mut Connection) -> Result<Vec<Capability>, Box<dyn Error>> {
+    conn.send(&ClientHello { version: PROTOCOL_VERSION, capabilities: Capability::iter().collect() })?;
+
+    match conn.receive::<ServerHello>()? {
+        ServerHello::Accepted { capabilities, .. } => Ok(capabilities),
+        ServerHello::Rejected { min_version, max_version } => Err(format!(
+            "The server supports the protocol versions {} to {} but this client speaks version {}, please use a compatible client",
+            min_version, max_version, PROTOCOL_VERSION
+        ))?,
+    }
+}
@@ -1,71 +1,150 @@
-/// This file is used to configure and start a TLS connection to the server.
-/// On new connections, the `client` function is called.
+//! This file is used to configure and start a TLS connection to the server.
+//! On new connections, the `client` function is called.
 
 mod connection;
 mod action;
+mod error;
+mod menu;
+mod handshake;
+mod tls;
+mod pin;
+mod config;
+mod command;
+mod output;
+mod password;
 
 use std::error::Error;
-use std::fs::File;
-use native_tls::{Certificate, Protocol, TlsConnector};
-use std::io::{Read};
 use std::net::TcpStream;
+use std::process;
+use clap::Parser;
 use read_input::prelude::*;
-use crate::action::Action;
+use crate::command::Cli;
+use crate::config::Profile;
 use crate::connection::Connection;
+use crate::error::{Closed, EXIT_CONNECTION, EXIT_USAGE};
+use crate::menu::Action;
+use crate::tls::{TlsConnector, TlsSettings};
+use lab3_protocol::{ProtocolError, Reply, Request};
 
-// Called once connected to the server, used to execute actions.
-fn client(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+// Called once connected to the server, used to execute actions. Returns the exit code of the
+// client, which tells why the last action before exiting was refused, 0 if it succeeded.
+fn client(profile: &Profile, connector: &TlsConnector) -> Result<i32, Box<dyn Error>> {
+    let mut conn = connect(profile, connector)?;
+    let mut last = Ok(());
     loop {
-        let banner = conn.receive::<String>()?;
-        println!("{}", banner);
+        match interact(&mut conn, profile, &mut last) {
+            Ok(Some(code)) => return Ok(code),
+            Ok(None) => output::note(""),
+            // A lost connection is resumed without logging in again, unless the server closed it
+            Err(e) if !e.is::<Closed>() && conn.token().is_some() => {
+                output::note(&format!("Connection lost: {}", e));
+                let token = conn.token().unwrap_or_default().to_string();
+                conn = resume(profile, connector, token)?;
+                output::note("Session resumed, the last action may not have been performed\n");
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Show the menu and perform the selected action, returns the exit code if the user exits
+fn interact(conn: &mut Connection, profile: &Profile, last: &mut Result<(), ProtocolError>) -> Result<Option<i32>, Box<dyn Error>> {
+    let banner = conn.banner()?;
+    output::note(&banner);
+
+    action::display();
 
-        Action::display();
-        let action = input::<Action>().msg("Please select: ").get();
+    // Do not send requests that the server would not understand
+    let action = loop {
+        let action = output::ask::<Action>().msg("Please select: ").get();
+        if action.capability().is_some_and(|c| !conn.supports(c)) {
+            output::refusal("Cannot perform the action", &ProtocolError::Unsupported);
+            *last = Err(ProtocolError::Unsupported);
+        } else {
+            break action;
+        }
+    };
 
-        action.perform(conn)?;
-        println!();
+    let res = action::perform(&action, conn, profile)?;
+    if action == Action::Exit {
+        return Ok(Some(last.as_ref().err().map_or(0, error::exit_code)));
     }
+    *last = res;
+    Ok(None)
 }
 
-// Load a PEM certificate
-#[allow(unused)]
-fn load_server_cert(cert_file: &str) -> Certificate {
-    let mut cert = Vec::new();
-    let mut cert_file = File::open(cert_file).unwrap();
+// Open a connection to the server and negotiate the protocol
+fn connect(profile: &Profile, connector: &TlsConnector) -> Result<Connection, Box<dyn Error>> {
+    let stream = TcpStream::connect((profile.host.as_str(), profile.port))
+        .map_err(|e| format!("Failed to connect to server {}:{}: {}", profile.host, profile.port, e))?;
+    let stream = connector.connect(&profile.host, stream)
+        .map_err(|e| format!("Failed to init TLS: {}", e))?;
 
-    cert_file.read_to_end(&mut cert).unwrap();
-    Certificate::from_pem(&cert).unwrap()
+    let mut conn = Connection::new(stream);
+    let capabilities = handshake::hello(&mut conn)?;
+    conn.set_capabilities(capabilities);
+    Ok(conn)
 }
 
-const SERVER_HOST: &str = "localhost";
-const SERVER_PORT: &str = "4444";
+// Open a new connection and resume the session of a lost connection with its token
+fn resume(profile: &Profile, connector: &TlsConnector, token: String) -> Result<Connection, Box<dyn Error>> {
+    let mut conn = connect(profile, connector)?;
+    conn.banner()?;
 
-fn main() {
-    let connector = TlsConnector::builder()
-        .min_protocol_version(Some(Protocol::Tlsv12))
-        .max_protocol_version(Some(Protocol::Tlsv12))
-        .add_root_certificate(load_server_cert("./tls/root/ec_cert.pem"))
-        .build()
-        .expect("Failed to build TlsConnector");
+    // The token is replaced by a new one, the old one cannot be used anymore
+    match conn.request(Request::Resume { token })? {
+        Ok(Reply::Session(token)) => {
+            conn.set_token(Some(token));
+            Ok(conn)
+        }
+        Ok(_) => Err("Unexpected reply from the server")?,
+        Err(e) => Err(format!("Cannot resume the session: {}", error::describe(&e)))?,
+    }
+}
 
-    let stream = match TcpStream::connect(format!("{}:{}", SERVER_HOST, SERVER_PORT)) {
-        Ok(stream) => stream,
+fn main() {
+    // Help and version are printed with the code 0, invalid arguments have their own code
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        let code = if e.use_stderr() { EXIT_USAGE } else { 0 };
+        let _ = e.print();
+        process::exit(code);
+    });
+    output::init(cli.output);
+    let profile = match Profile::load(&cli.profile) {
+        Ok(profile) => profile,
         Err(e) => {
-            eprintln!("Failed to connect to server: {}", e);
-            return;
+            output::failure("configuration", &format!("Invalid configuration: {}", e), EXIT_CONNECTION);
+            process::exit(EXIT_CONNECTION);
         }
     };
 
-    let stream = match connector.connect(SERVER_HOST, stream) {
-        Ok(stream) => stream,
+    let connector = match TlsSettings::new(&profile) {
+        Ok(settings) => {
+            if settings.pins.len() == 1 {
+                output::warning("single_pin", "only one server key is pinned, add a backup pin to be able to rotate it");
+            }
+            TlsConnector::new(&settings)
+        }
+        Err(e) => Err(e.into()),
+    };
+    let connector = match connector {
+        Ok(connector) => connector,
         Err(e) => {
-            eprintln!("Failed to init TLS: {}", e);
-            return;
+            output::failure("configuration", &format!("Failed to configure TLS: {}", e), EXIT_CONNECTION);
+            process::exit(EXIT_CONNECTION);
         }
     };
 
-    let mut conn = Connection::new(stream);
-    if let Err(e) = client(&mut conn) {
-        eprintln!("{}", e);
+    // A command given on the command line is run without the menu
+    if let Some(command) = cli.command {
+        process::exit(command::run(command, cli.credentials, &profile, &connector));
+    }
+
+    match client(&profile, &connector) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            output::failure("connection", &e.to_string(), EXIT_CONNECTION);
+            process::exit(EXIT_CONNECTION);
+        }
     }
 }
@@ -0,0 +1,53 @@
+//! This file is used to define the entries of the client menu
+
+use lab3_protocol::Capability;
+use strum_macros::{Display, EnumIter, EnumString};
+
+/// Actions selected by the client, the label is displayed in the client menu and the number
+/// can be typed to select it
+#[derive(Clone, Copy, Debug, PartialEq, Display, EnumString, EnumIter)]
+pub enum Action {
+    #[strum(serialize = "Show users", serialize = "1")]
+    ShowUsers,
+    #[strum(serialize = "Change my phone number", serialize = "2")]
+    ChangeOwnPhone,
+    #[strum(serialize = "Change someone's phone number", serialize = "3")]
+    ChangePhone,
+    #[strum(serialize = "Add user", serialize = "4")]
+    AddUser,
+    #[strum(serialize = "Login", serialize = "5")]
+    Login,
+    #[strum(serialize = "Logout", serialize = "6")]
+    Logout,
+    #[strum(serialize = "Backup database", serialize = "7")]
+    Backup,
+    #[strum(serialize = "Restore database", serialize = "8")]
+    Restore,
+    #[strum(serialize = "Show someone's history", serialize = "9")]
+    ShowHistory,
+    #[strum(serialize = "Revert someone's phone number", serialize = "10")]
+    RevertPhone,
+    #[strum(serialize = "Query audit log", serialize = "11")]
+    QueryAudit,
+    #[strum(serialize = "Revoke someone's sessions", serialize = "12")]
+    RevokeSessions,
+    #[strum(serialize = "Show connected sessions", serialize = "13")]
+    ShowSessions,
+    #[strum(serialize = "Terminate a session", serialize = "14")]
+    TerminateSession,
+    #[strum(serialize = "Exit", serialize = "15")]
+    Exit,
+}
+
+impl Action {
+    /// Capability which the server must support to perform this action, if any
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Action::ShowHistory | Action::RevertPhone => Some(Capability::History),
+            Action::Backup | Action::Restore => Some(Capability::Backup),
+            Action::QueryAudit => Some(Capability::Audit),
+            Action::RevokeSessions | Action::ShowSessions | Action::TerminateSession => Some(Capability::Sessions),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,533 @@
+//! This file is used to print the results and the refusals of the actions.
+//!
+//! The table format is meant to be read. The JSON, YAML and CSV formats are meant to be parsed:
+//! their fields are part of the interface of the client and are only ever added to. The dates
+//! are Unix timestamps in seconds. The CSV output has one row per record, with a header.
+//! The refusals and failures are printed on the standard error, in the same format.
+
+use crate::error::{describe, exit_code};
+use clap::ValueEnum;
+use lab3_protocol::{ProtocolError, Resource, SessionInfo, UserInfo};
+use read_input::prelude::*;
+use read_input::InputBuilder;
+use serde::Serialize;
+use std::error::Error;
+use std::io::{stderr, stdout, Write};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use time::format_description::FormatItem;
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+const DATE_FORMAT: &[FormatItem<'static>] = format_description!("[day].[month].[year] [hour]:[minute]:[second]");
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Format {
+    #[default]
+    Table,
+    Json,
+    Csv,
+    Yaml,
+}
+
+/// Choose the format of every output, the table is used if it is not set
+pub fn init(format: Format) {
+    FORMAT.set(format).expect("output format already set");
+}
+
+fn format() -> Format {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Ask a value in the menu. The prompts are written on the standard error when the results are
+/// meant to be parsed, so that the standard output only has the results.
+pub fn ask<T: FromStr>() -> InputBuilder<T> {
+    match format() {
+        Format::Table => input::<T>(),
+        _ => input::<T>().prompting_on_stderr(),
+    }
+}
+
+/// Print a message of the menu, on the standard error unless the results are printed as tables
+pub fn note(message: &str) {
+    match format() {
+        Format::Table => println!("{}", message),
+        _ => eprintln!("{}", message),
+    }
+}
+
+/// Result of an action which can be printed in every format
+pub trait Report: Serialize {
+    /// Header of the CSV output
+    const COLUMNS: &'static [&'static str];
+
+    /// Records of the CSV output
+    fn rows(&self) -> Vec<Vec<String>>;
+
+    /// Text of the table output, nothing is printed if it is empty
+    fn table(&self) -> Result<String, Box<dyn Error>>;
+}
+
+/// Print the result of an action on the standard output
+pub fn show<R: Report>(report: &R) -> Result<(), Box<dyn Error>> {
+    write(&mut stdout(), report)
+}
+
+fn write<R: Report>(out: &mut dyn Write, report: &R) -> Result<(), Box<dyn Error>> {
+    match format() {
+        Format::Table => {
+            let table = report.table()?;
+            if !table.is_empty() {
+                writeln!(out, "{}", table)?;
+            }
+        }
+        Format::Json => writeln!(out, "{}", serde_json::to_string_pretty(report)?)?,
+        Format::Yaml => write!(out, "{}", serde_yaml::to_string(report)?)?,
+        Format::Csv => {
+            let mut csv = csv::Writer::from_writer(out);
+            csv.write_record(R::COLUMNS)?;
+            for row in report.rows() {
+                csv.write_record(row)?;
+            }
+            csv.flush()?;
+        }
+    }
+    Ok(())
+}
+
+fn date(timestamp: i64) -> Result<String, Box<dyn Error>> {
+    Ok(OffsetDateTime::from_unix_timestamp(timestamp)?.format(DATE_FORMAT)?)
+}
+
+fn or_dash(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or("-")
+}
+
+/// The action was performed and has nothing to return
+#[derive(Serialize)]
+pub struct Done {
+    pub status: &'static str,
+}
+
+impl Default for Done {
+    fn default() -> Done {
+        Done { status: "done" }
+    }
+}
+
+impl Report for Done {
+    const COLUMNS: &'static [&'static str] = &["status"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![self.status.to_string()]]
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        Ok(String::new())
+    }
+}
+
+#[derive(Serialize)]
+pub struct User {
+    pub username: String,
+    pub phone: String,
+}
+
+#[derive(Serialize)]
+pub struct Users {
+    pub users: Vec<User>,
+}
+
+impl From<Vec<UserInfo>> for Users {
+    fn from(users: Vec<UserInfo>) -> Users {
+        Users { users: users.into_iter().map(|u| User { username: u.username, phone: u.phone_number }).collect() }
+    }
+}
+
+impl Report for Users {
+    const COLUMNS: &'static [&'static str] = &["username", "phone"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.users.iter().map(|u| vec![u.username.clone(), u.phone.clone()]).collect()
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        Ok(self.users.iter().map(|u| format!("{} - {}", u.username, u.phone)).collect::<Vec<_>>().join("\n"))
+    }
+}
+
+/// Change of a user account, the version 0 is its creation
+#[derive(Serialize)]
+pub struct Version {
+    pub version: u32,
+    pub actor: String,
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+    pub timestamp: i64,
+}
+
+#[derive(Serialize)]
+pub struct History {
+    pub username: String,
+    pub versions: Vec<Version>,
+}
+
+impl Report for History {
+    const COLUMNS: &'static [&'static str] = &["version", "actor", "field", "old_value", "new_value", "timestamp"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.versions.iter()
+            .map(|v| vec![v.version.to_string(), v.actor.clone(), v.field.clone(), v.old_value.clone(), v.new_value.clone(), v.timestamp.to_string()])
+            .collect()
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        let mut lines = vec!["Version 0 - account creation".to_string()];
+        for v in &self.versions {
+            lines.push(format!("Version {} - {} changed {} from {} to {} on {}",
+                               v.version, v.actor, v.field, v.old_value, v.new_value, date(v.timestamp)?));
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditEvent {
+    pub seq: u64,
+    pub timestamp: i64,
+    pub actor: Option<String>,
+    pub event: String,
+    pub target: Option<String>,
+    pub outcome: String,
+}
+
+/// Page of the audit events matching a query, the pages start at 1
+#[derive(Serialize)]
+pub struct AuditPage {
+    pub page: u32,
+    pub pages: u64,
+    pub total: u64,
+    pub events: Vec<AuditEvent>,
+}
+
+impl Report for AuditPage {
+    const COLUMNS: &'static [&'static str] = &["seq", "timestamp", "actor", "event", "target", "outcome"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.events.iter()
+            .map(|e| vec![
+                e.seq.to_string(), e.timestamp.to_string(), e.actor.clone().unwrap_or_default(),
+                e.event.clone(), e.target.clone().unwrap_or_default(), e.outcome.clone(),
+            ])
+            .collect()
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        let mut lines = vec![format!("{:<6} {:<20} {:<20} {:<15} {:<20} {:<8}", "#", "Date", "Actor", "Action", "Target", "Outcome")];
+        for e in &self.events {
+            lines.push(format!("{:<6} {:<20} {:<20} {:<15} {:<20} {:<8}",
+                               e.seq, date(e.timestamp)?, or_dash(&e.actor), e.event, or_dash(&e.target), e.outcome));
+        }
+        lines.push(format!("Page {}/{} ({} matching events)", self.page, self.pages, self.total));
+        Ok(lines.join("\n"))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Session {
+    pub id: u64,
+    pub username: Option<String>,
+    pub address: String,
+    pub connected_at: i64,
+    pub last_activity: i64,
+}
+
+#[derive(Serialize)]
+pub struct Sessions {
+    pub sessions: Vec<Session>,
+}
+
+impl From<Vec<SessionInfo>> for Sessions {
+    fn from(sessions: Vec<SessionInfo>) -> Sessions {
+        Sessions {
+            sessions: sessions.into_iter().map(|s| Session {
+                id: s.id,
+                username: s.username,
+                address: s.peer,
+                connected_at: s.connected_at,
+                last_activity: s.last_activity,
+            }).collect(),
+        }
+    }
+}
+
+impl Report for Sessions {
+    const COLUMNS: &'static [&'static str] = &["id", "username", "address", "connected_at", "last_activity"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        self.sessions.iter()
+            .map(|s| vec![
+                s.id.to_string(), s.username.clone().unwrap_or_default(), s.address.clone(),
+                s.connected_at.to_string(), s.last_activity.to_string(),
+            ])
+            .collect()
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        let mut lines = vec![format!("{:<6} {:<20} {:<25} {:<20} {:<20}", "Id", "User", "Address", "Connected", "Last activity")];
+        for s in &self.sessions {
+            lines.push(format!("{:<6} {:<20} {:<25} {:<20} {:<20}",
+                               s.id, or_dash(&s.username), s.address, date(s.connected_at)?, date(s.last_activity)?));
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    pub snapshot: String,
+}
+
+impl Report for Snapshot {
+    const COLUMNS: &'static [&'static str] = &["snapshot"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![self.snapshot.clone()]]
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        Ok(format!("Snapshot created: {}", self.snapshot))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Revoked {
+    pub revoked: u64,
+}
+
+impl Report for Revoked {
+    const COLUMNS: &'static [&'static str] = &["revoked"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![self.revoked.to_string()]]
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        Ok(format!("{} session(s) revoked", self.revoked))
+    }
+}
+
+/// Why an action failed, printed as `{"error": {...}}`
+#[derive(Serialize)]
+struct ErrorReport {
+    error: Failure,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    /// Kind of the failure, for example forbidden, not_found or connection
+    code: &'static str,
+    message: String,
+    exit_code: i32,
+    /// What the table output prints before the message
+    #[serde(skip)]
+    context: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resource: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<String>,
+}
+
+impl Report for ErrorReport {
+    const COLUMNS: &'static [&'static str] = &["code", "message", "exit_code", "resource", "field", "rule"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        let e = &self.error;
+        vec![vec![
+            e.code.to_string(), e.message.clone(), e.exit_code.to_string(),
+            e.resource.clone().unwrap_or_default(), e.field.clone().unwrap_or_default(), e.rule.clone().unwrap_or_default(),
+        ]]
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        match self.error.context.as_str() {
+            "" => Ok(self.error.message.clone()),
+            context => Ok(format!("{}: {}", context, self.error.message)),
+        }
+    }
+}
+
+/// Print a refusal of the server on the standard error
+pub fn refusal(context: &str, e: &ProtocolError) {
+    let (code, resource, field, rule) = match e {
+        ProtocolError::Unauthenticated => ("unauthenticated", None, None, None),
+        ProtocolError::Forbidden => ("forbidden", None, None, None),
+        ProtocolError::NotFound(r) => ("not_found", Some(resource_name(r)), None, None),
+        ProtocolError::AlreadyExists(r) => ("already_exists", Some(resource_name(r)), None, None),
+        ProtocolError::Validation { field, rule } => ("validation", None, Some(field.clone()), Some(rule.clone())),
+        ProtocolError::RateLimited => ("rate_limited", None, None, None),
+        ProtocolError::Unsupported => ("unsupported", None, None, None),
+        ProtocolError::Internal => ("internal", None, None, None),
+    };
+    print_failure(Failure { code, message: describe(e), exit_code: exit_code(e), context: context.to_string(), resource, field, rule });
+}
+
+/// Print a failure of the client on the standard error, `code` is `connection` or `usage`
+pub fn failure(code: &'static str, message: &str, exit_code: i32) {
+    print_failure(Failure {
+        code,
+        message: message.to_string(),
+        exit_code,
+        context: String::new(),
+        resource: None,
+        field: None,
+        rule: None,
+    });
+}
+
+fn print_failure(error: Failure) {
+    // Nothing else can be done if the standard error is closed
+    let _ = write(&mut stderr(), &ErrorReport { error });
+}
+
+/// Something the user should fix which does not stop the client, printed as `{"warning": {...}}`
+#[derive(Serialize)]
+struct WarningReport {
+    warning: Warning,
+}
+
+#[derive(Serialize)]
+struct Warning {
+    /// Kind of the warning, for example single_pin
+    code: &'static str,
+    message: String,
+}
+
+impl Report for WarningReport {
+    const COLUMNS: &'static [&'static str] = &["code", "message"];
+
+    fn rows(&self) -> Vec<Vec<String>> {
+        vec![vec![self.warning.code.to_string(), self.warning.message.clone()]]
+    }
+
+    fn table(&self) -> Result<String, Box<dyn Error>> {
+        Ok(format!("Warning: {}", self.warning.message))
+    }
+}
+
+/// Print a warning on the standard error
+pub fn warning(code: &'static str, message: &str) {
+    // Nothing else can be done if the standard error is closed
+    let _ = write(&mut stderr(), &WarningReport { warning: Warning { code, message: message.to_string() } });
+}
+
+fn resource_name(resource: &Resource) -> String {
+    resource.to_string().to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // The fields are parsed by scripts, renaming one breaks them
+    #[test]
+    fn json_schema() {
+        let users = Users::from(vec![UserInfo::new("alice".to_string(), "0791234567".to_string())]);
+        assert_eq!(serde_json::to_value(&users).unwrap(), json!({"users": [{"username": "alice", "phone": "0791234567"}]}));
+
+        let error = ErrorReport {
+            error: Failure {
+                code: "validation",
+                message: "invalid phone number".to_string(),
+                exit_code: 7,
+                context: "Error while changing phone".to_string(),
+                resource: None,
+                field: Some("phone number".to_string()),
+                rule: Some("must have the format 0xxxxxxxxx".to_string()),
+            },
+        };
+        assert_eq!(serde_json::to_value(&error).unwrap(), json!({"error": {
+            "code": "validation",
+            "message": "invalid phone number",
+            "exit_code": 7,
+            "field": "phone number",
+            "rule": "must have the format 0xxxxxxxxx",
+        }}));
+
+        let warning = WarningReport { warning: Warning { code: "single_pin", message: "only one server key is pinned".to_string() } };
+        assert_eq!(serde_json::to_value(&warning).unwrap(), json!({"warning": {"code": "single_pin", "message": "only one server key is pinned"}}));
+    }
+
+    #[test]
+    fn json_schema_of_the_results() {
+        let history = History {
+            username: "alice".to_string(),
+            versions: vec![Version {
+                version: 1,
+                actor: "bob".to_string(),
+                field: "phone".to_string(),
+                old_value: "0791234567".to_string(),
+                new_value: "0797654321".to_string(),
+                timestamp: 1700000000,
+            }],
+        };
+        assert_eq!(serde_json::to_value(&history).unwrap(), json!({"username": "alice", "versions": [{
+            "version": 1,
+            "actor": "bob",
+            "field": "phone",
+            "old_value": "0791234567",
+            "new_value": "0797654321",
+            "timestamp": 1700000000,
+        }]}));
+
+        let audit = AuditPage {
+            page: 1,
+            pages: 2,
+            total: 21,
+            events: vec![AuditEvent {
+                seq: 4,
+                timestamp: 1700000000,
+                actor: Some("bob".to_string()),
+                event: "ChangePhone".to_string(),
+                target: None,
+                outcome: "Success".to_string(),
+            }],
+        };
+        assert_eq!(serde_json::to_value(&audit).unwrap(), json!({"page": 1, "pages": 2, "total": 21, "events": [{
+            "seq": 4,
+            "timestamp": 1700000000,
+            "actor": "bob",
+            "event": "ChangePhone",
+            "target": null,
+            "outcome": "Success",
+        }]}));
+
+        let sessions = Sessions {
+            sessions: vec![Session {
+                id: 3,
+                username: Some("alice".to_string()),
+                address: "127.0.0.1:50000".to_string(),
+                connected_at: 1700000000,
+                last_activity: 1700000060,
+            }],
+        };
+        assert_eq!(serde_json::to_value(&sessions).unwrap(), json!({"sessions": [{
+            "id": 3,
+            "username": "alice",
+            "address": "127.0.0.1:50000",
+            "connected_at": 1700000000,
+            "last_activity": 1700000060,
+        }]}));
+
+        assert_eq!(serde_json::to_value(Revoked { revoked: 2 }).unwrap(), json!({"revoked": 2}));
+        let snapshot = Snapshot { snapshot: "db_1.ron".to_string() };
+        assert_eq!(serde_json::to_value(&snapshot).unwrap(), json!({"snapshot": "db_1.ron"}));
+        assert_eq!(serde_json::to_value(Done::default()).unwrap(), json!({"status": "done"}));
+    }
+}
@@ -0,0 +1,47 @@
+//! This file is used to read passwords without showing them.
+//!
+//! In a terminal, the passwords are typed without echo. Otherwise, for example when the input
+//! is piped, they are read as lines of the standard input.
+
+use crate::output;
+use std::io::{self, stdin, BufRead, IsTerminal, Read};
+use zeroize::Zeroizing;
+
+/// Longest line read from the standard input. The buffer is allocated once with this size, it
+/// never grows, which would leave a copy of the password in the memory it frees.
+const MAX_LINE: usize = 1024;
+
+/// Read a password, the prompt is only shown in a terminal. It is erased from memory once dropped.
+pub fn read(prompt: &str) -> io::Result<Zeroizing<String>> {
+    if stdin().is_terminal() {
+        return rpassword::prompt_password(prompt).map(Zeroizing::new);
+    }
+
+    let mut line = Zeroizing::new(String::with_capacity(MAX_LINE));
+    if stdin().lock().take(MAX_LINE as u64).read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no password on the standard input"));
+    }
+    if line.len() == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "password too long on the standard input"));
+    }
+    // Removing the end of line in place does not leave a copy of the password
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Read a new password, typed twice in a terminal until both are the same
+pub fn read_new(prompt: &str) -> io::Result<Zeroizing<String>> {
+    if !stdin().is_terminal() {
+        return read(prompt);
+    }
+
+    loop {
+        let password = read(prompt)?;
+        let confirmation = read("Please confirm the password: ")?;
+        if password == confirmation {
+            return Ok(password);
+        }
+        output::note("The passwords do not match, please try again");
+    }
+}
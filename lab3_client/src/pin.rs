@@ -0,0 +1,68 @@
+//! This file is used to pin the public key of the server.
+//!
+//! A pin is the SHA-256 fingerprint of the SubjectPublicKeyInfo of the server certificate, in
+//! hexadecimal. The bytes can be separated by colons. It can be computed with:
+//!     openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256
+//! With pins, the server certificate is only trusted if its key matches one of them. A backup
+//! pin of the next key allows to rotate the key of the server without changing the clients.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(feature = "rustls")]
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pin([u8; 32]);
+
+impl Pin {
+    /// Fingerprint of a DER encoded SubjectPublicKeyInfo
+    #[cfg(feature = "rustls")]
+    pub fn of(spki: &[u8]) -> Pin {
+        Pin(Sha256::digest(spki).into())
+    }
+}
+
+impl FromStr for Pin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Pin, String> {
+        let hex: String = s.trim().chars().filter(|c| *c != ':').collect();
+        let mut bytes = [0u8; 32];
+        if hex.len() != 2 * bytes.len() || !hex.is_ascii() {
+            return Err(format!("invalid pin \"{}\", expected a SHA-256 fingerprint of 64 hexadecimal digits", s));
+        }
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .map_err(|_| format!("invalid pin \"{}\", it must only have hexadecimal digits", s))?;
+        }
+        Ok(Pin(bytes))
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pin() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let pin: Pin = hex.parse().unwrap();
+        assert_eq!(pin.to_string(), hex.to_lowercase());
+
+        let colons = hex.as_bytes().chunks(2).map(|c| std::str::from_utf8(c).unwrap()).collect::<Vec<_>>().join(":");
+        assert_eq!(colons.parse::<Pin>().unwrap(), pin);
+
+        assert!("0011".parse::<Pin>().is_err());
+        assert!(hex.replace('0', "g").parse::<Pin>().is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! This file is used to configure the TLS transport of the client.
+//!
+//! Two backends are available: native-tls, which is limited to TLS 1.2, and rustls, which only
+//! negotiates TLS 1.3 with the cipher suites of `CIPHER_SUITES`. The backend is chosen with the
+//! `TLS_BACKEND` environment variable. rustls is the default when the client is built with the
+//! `rustls` feature. Both backends trust the same PEM root certificate.
+//!
+//! With the rustls backend, pinning the server key switches to strict pinning: see the `pin`
+//! module.
+//!
+//! A client certificate and its PKCS8 key can be given with `CLIENT_CERT` and `CLIENT_KEY`, the
+//! server then logs in the user named by the certificate.
+
+use crate::connection::Stream;
+use crate::config::Profile;
+use crate::pin::Pin;
+use native_tls::{Certificate, Identity, Protocol};
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::net::TcpStream;
+
+#[cfg(feature = "rustls")]
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider, WebPkiSupportedAlgorithms};
+#[cfg(feature = "rustls")]
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+#[cfg(feature = "rustls")]
+use rustls::client::WebPkiServerVerifier;
+#[cfg(feature = "rustls")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+#[cfg(feature = "rustls")]
+use rustls::{version, CertificateError, ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned, SupportedCipherSuite};
+#[cfg(feature = "rustls")]
+use std::io::BufReader;
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+#[cfg(feature = "rustls")]
+use x509_parser::prelude::{ASN1Time, FromDer, GeneralName, X509Certificate};
+
+/// Cipher suites offered by the rustls backend, in order of preference
+#[cfg(feature = "rustls")]
+pub const CIPHER_SUITES: [SupportedCipherSuite; 3] = [
+    ring::cipher_suite::TLS13_AES_256_GCM_SHA384,
+    ring::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+    ring::cipher_suite::TLS13_AES_128_GCM_SHA256,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// TLS 1.2 through the TLS library of the system
+    NativeTls,
+    /// TLS 1.3 through rustls
+    Rustls,
+}
+
+impl Backend {
+    /// Read the backend from `TLS_BACKEND`, or use the default one
+    pub fn from_env() -> Result<Backend, String> {
+        match env::var("TLS_BACKEND").as_deref() {
+            Ok("native-tls") => Ok(Backend::NativeTls),
+            Ok("rustls") if cfg!(feature = "rustls") => Ok(Backend::Rustls),
+            Ok("rustls") => Err("TLS_BACKEND is rustls but the client was built without the rustls feature".to_string()),
+            Ok(other) => Err(format!("TLS_BACKEND must be native-tls or rustls, found \"{}\"", other)),
+            Err(_) if cfg!(feature = "rustls") => Ok(Backend::Rustls),
+            Err(_) => Ok(Backend::NativeTls),
+        }
+    }
+}
+
+/// Certificate sent to the server to log in without a password
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity {
+    pub cert_file: String,
+    /// PKCS8 private key of the certificate
+    pub key_file: String,
+}
+
+impl ClientIdentity {
+    /// Read `CLIENT_CERT` and `CLIENT_KEY`, no certificate is sent if they are not set
+    pub fn from_env() -> Result<Option<ClientIdentity>, String> {
+        match (env::var("CLIENT_CERT"), env::var("CLIENT_KEY")) {
+            (Ok(cert_file), Ok(key_file)) => Ok(Some(ClientIdentity { cert_file, key_file })),
+            (Err(_), Err(_)) => Ok(None),
+            _ => Err("CLIENT_CERT and CLIENT_KEY must be set together".to_string()),
+        }
+    }
+}
+
+/// Everything needed to build the connector
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    pub backend: Backend,
+    /// PEM file of the root certificate of the server, not needed if the server key is pinned
+    pub ca_file: Option<String>,
+    pub identity: Option<ClientIdentity>,
+    /// Fingerprints of the accepted server keys, the roots are not used if there are any
+    pub pins: Vec<Pin>,
+}
+
+impl TlsSettings {
+    /// Read the backend and the client certificate from the environment, the way to trust the
+    /// server comes from the profile
+    pub fn new(profile: &Profile) -> Result<TlsSettings, String> {
+        Ok(TlsSettings {
+            backend: Backend::from_env()?,
+            ca_file: profile.ca_file.clone(),
+            identity: ClientIdentity::from_env()?,
+            pins: profile.pins.clone(),
+        })
+    }
+}
+
+pub enum TlsConnector {
+    NativeTls(native_tls::TlsConnector),
+    #[cfg(feature = "rustls")]
+    Rustls(Arc<ClientConfig>),
+}
+
+impl TlsConnector {
+    /// Create the connector of a backend trusting the root certificate or the pinned keys
+    pub fn new(settings: &TlsSettings) -> Result<TlsConnector, Box<dyn Error>> {
+        let (ca_file, identity) = (settings.ca_file.as_deref(), settings.identity.as_ref());
+        match settings.backend {
+            Backend::NativeTls if !settings.pins.is_empty() => Err("Pinning the server key requires the rustls backend")?,
+            Backend::NativeTls => {
+                let ca_file = ca_file.ok_or("The native-tls backend requires a root certificate")?;
+                Ok(TlsConnector::NativeTls(native_tls_config(ca_file, identity)?))
+            }
+            #[cfg(feature = "rustls")]
+            Backend::Rustls => Ok(TlsConnector::Rustls(rustls_config(ca_file, identity, &settings.pins)?)),
+            #[cfg(not(feature = "rustls"))]
+            Backend::Rustls => Err("The client was built without the rustls feature")?,
+        }
+    }
+
+    /// Perform the TLS handshake on top of a new connection
+    pub fn connect(&self, host: &str, stream: TcpStream) -> Result<Box<dyn Stream>, Box<dyn Error>> {
+        match self {
+            TlsConnector::NativeTls(connector) => Ok(Box::new(connector.connect(host, stream)?)),
+            #[cfg(feature = "rustls")]
+            TlsConnector::Rustls(config) => {
+                let server_name = ServerName::try_from(host.to_string())?;
+                let mut tls = StreamOwned::new(ClientConnection::new(config.clone(), server_name)?, stream);
+                // rustls performs the handshake lazily, its errors are reported here instead
+                while tls.conn.is_handshaking() {
+                    tls.conn.complete_io(&mut tls.sock)?;
+                }
+                Ok(Box::new(tls))
+            }
+        }
+    }
+}
+
+// Read a whole PEM file
+fn read_pem(file: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut pem = Vec::new();
+    File::open(file)
+        .and_then(|mut f| f.read_to_end(&mut pem))
+        .map_err(|e| format!("Cannot read \"{}\": {}", file, e))?;
+    Ok(pem)
+}
+
+// Create a new TLS configuration, native-tls does not support TLS 1.3 on every platform
+fn native_tls_config(ca_file: &str, identity: Option<&ClientIdentity>) -> Result<native_tls::TlsConnector, Box<dyn Error>> {
+    let mut builder = native_tls::TlsConnector::builder();
+    builder
+        .min_protocol_version(Some(Protocol::Tlsv12))
+        .max_protocol_version(Some(Protocol::Tlsv12))
+        .add_root_certificate(Certificate::from_pem(&read_pem(ca_file)?)?);
+    if let Some(identity) = identity {
+        builder.identity(Identity::from_pkcs8(&read_pem(&identity.cert_file)?, &read_pem(&identity.key_file)?)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Cryptographic provider restricted to the allowed cipher suites
+#[cfg(feature = "rustls")]
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(CryptoProvider { cipher_suites: CIPHER_SUITES.to_vec(), ..ring::default_provider() })
+}
+
+// Read the certificates of a PEM file
+#[cfg(feature = "rustls")]
+fn load_certs(file: &str) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(&read_pem(file)?[..])).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate \"{}\": {}", file, e))?;
+    if certs.is_empty() {
+        Err(format!("No certificate found in \"{}\"", file))?
+    }
+    Ok(certs)
+}
+
+// Read the private key of a PEM file
+#[cfg(feature = "rustls")]
+fn load_key(file: &str) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    Ok(rustls_pemfile::private_key(&mut BufReader::new(&read_pem(file)?[..]))
+        .map_err(|e| format!("Invalid private key \"{}\": {}", file, e))?
+        .ok_or_else(|| format!("No private key found in \"{}\"", file))?)
+}
+
+// Create a TLS 1.3 configuration with rustls
+#[cfg(feature = "rustls")]
+fn rustls_config(ca_file: Option<&str>, identity: Option<&ClientIdentity>, pins: &[Pin]) -> Result<Arc<ClientConfig>, Box<dyn Error>> {
+    let provider = crypto_provider();
+    let (certs, webpki) = match ca_file {
+        Some(ca_file) => {
+            let certs = load_certs(ca_file)?;
+            let mut roots = RootCertStore::empty();
+            roots.add_parsable_certificates(certs.iter().cloned());
+            (certs, Some(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?))
+        }
+        None if !pins.is_empty() => (Vec::new(), None),
+        None => Err("A root certificate or a pinned key is required to trust the server")?,
+    };
+
+    let verifier = ServerVerifier {
+        roots: certs,
+        pins: pins.to_vec(),
+        webpki,
+        algorithms: provider.signature_verification_algorithms,
+    };
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let config = match identity {
+        Some(identity) => builder.with_client_auth_cert(load_certs(&identity.cert_file)?, load_key(&identity.key_file)?)?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Verifier of the server certificate.
+///
+/// With pins, the certificate is only trusted if its key matches one of them, the roots are not
+/// used. Otherwise, a certificate which is exactly one of the roots is trusted: the certificates
+/// in `tls/` are self-signed CA certificates without subjectAltName, which webpki refuses as
+/// server certificates. In both cases, the validity period and the name are still checked. Any
+/// other certificate must chain to a root and is verified by webpki.
+#[cfg(feature = "rustls")]
+#[derive(Debug)]
+struct ServerVerifier {
+    roots: Vec<CertificateDer<'static>>,
+    pins: Vec<Pin>,
+    /// Verifier of the chain to the roots, there is none without roots
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    /// Signature algorithms of the provider, to check the handshake signature
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+#[cfg(feature = "rustls")]
+impl ServerCertVerifier for ServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let pinned = !self.pins.is_empty();
+        if !pinned && !self.roots.iter().any(|root| root.as_ref() == end_entity.as_ref()) {
+            return match &self.webpki {
+                Some(webpki) => webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now),
+                None => Err(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer)),
+            };
+        }
+
+        let (_, cert) = X509Certificate::from_der(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if pinned {
+            let pin = Pin::of(cert.public_key().raw);
+            if !self.pins.contains(&pin) {
+                let pins: Vec<String> = self.pins.iter().map(Pin::to_string).collect();
+                return Err(rustls::Error::General(format!(
+                    "the key of the server certificate \"{}\" does not match any pinned key. \
+                     Its fingerprint is {}, the pins are {}. The server key may have been replaced \
+                     or the connection intercepted, the connection was aborted",
+                    cert.subject(), pin, pins.join(", "))));
+            }
+        }
+
+        let now = ASN1Time::from_timestamp(now.as_secs() as i64)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if now < cert.validity().not_before {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::NotValidYet));
+        }
+        if now > cert.validity().not_after {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Expired));
+        }
+        if !certificate_names(&cert).iter().any(|name| *name == server_name.to_str()) {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// DNS names of a certificate: its subjectAltName, or its common name when it has none
+#[cfg(feature = "rustls")]
+fn certificate_names<'a>(cert: &'a X509Certificate<'a>) -> Vec<&'a str> {
+    match cert.subject_alternative_name() {
+        Ok(Some(san)) => san.value.general_names.iter().filter_map(|name| match name {
+            GeneralName::DNSName(dns) => Some(*dns),
+            _ => None,
+        }).collect(),
+        _ => cert.subject().iter_common_name().filter_map(|cn| cn.as_str().ok()).collect(),
+    }
+}
@@ -0,0 +1,68 @@
+//! This file is used to define the messages used to query the audit log
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+/// Security-relevant operations
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Display, EnumString)]
+pub enum Event {
+    Login,
+    Logout,
+    ChangeOwnPhone,
+    ChangePhone,
+    AddUser,
+    Backup,
+    Restore,
+    ShowHistory,
+    RevertPhone,
+    QueryAudit,
+    /// The server logged out a user whose session expired
+    SessionExpired,
+    Resume,
+    RevokeSessions,
+    TerminateSession,
+    /// HR listed the connected sessions and their addresses
+    ListSessions,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Display, EnumString)]
+pub enum Outcome {
+    /// The operation was performed
+    Success,
+    /// The operation was refused because of invalid credentials or inputs
+    Failure,
+    /// The operation was refused by the access control
+    Denied,
+}
+
+/// Filters of an audit query, the events must match every filter that is set.
+/// The time range includes `from` and excludes `to`, pages start at 0.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Query {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub event: Option<Event>,
+    pub outcome: Option<Outcome>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub page: u32,
+    pub page_size: u32,
+}
+
+/// Event as returned to the auditors
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EventInfo {
+    pub seq: u64,
+    pub timestamp: i64,
+    pub actor: Option<String>,
+    pub event: Event,
+    pub target: Option<String>,
+    pub outcome: Outcome,
+}
+
+/// A page of events matching a query, with the total number of matching events
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Page {
+    pub events: Vec<EventInfo>,
+    pub total: u64,
+}
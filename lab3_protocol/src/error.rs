@@ -0,0 +1,36 @@
+//! This file is used to define the errors returned by the server when it refuses a request
+
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+
+/// Kind of object a request refers to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Display)]
+pub enum Resource {
+    User,
+    Version,
+    Snapshot,
+    Session,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ProtocolError {
+    /// The request requires to be logged in, or the credentials are invalid
+    Unauthenticated,
+    /// The access control refused the request
+    Forbidden,
+    NotFound(Resource),
+    AlreadyExists(Resource),
+    /// An input does not respect the server policy
+    Validation { field: String, rule: String },
+    RateLimited,
+    /// The request needs a capability which was not negotiated during the hello
+    Unsupported,
+    /// The server failed to perform the request, the details are only logged by the server
+    Internal,
+}
+
+impl ProtocolError {
+    pub fn validation(field: &str, rule: &str) -> ProtocolError {
+        ProtocolError::Validation { field: field.to_string(), rule: rule.to_string() }
+    }
+}
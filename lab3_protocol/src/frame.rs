@@ -0,0 +1,131 @@
+//! This file is used to frame the messages on the wire.
+//!
+//! Every message is sent as a frame: a 4 bytes big-endian length followed by the bincode
+//! payload. Frames larger than `MAX_FRAME_SIZE` are rejected before anything is allocated,
+//! and bincode is limited to the same size so that a payload cannot announce a bigger value.
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::error::Error;
+use std::io::{Read, Write};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum size of a message payload in bytes
+pub const MAX_FRAME_SIZE: u32 = 1024 * 1024;
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_FRAME_SIZE as u64)
+        .reject_trailing_bytes()
+}
+
+/// Encode a message as a frame payload
+pub fn serialize<T: Serialize>(o: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(bincode_options().serialize(o)?)
+}
+
+/// Decode a frame payload, the whole payload must be consumed
+pub fn deserialize<T: DeserializeOwned>(payload: &[u8]) -> Result<T, Box<dyn Error>> {
+    Ok(bincode_options().deserialize(payload)?)
+}
+
+pub fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+    if payload.len() > MAX_FRAME_SIZE as usize {
+        Err(format!("Message of {} bytes exceeds the maximum of {} bytes", payload.len(), MAX_FRAME_SIZE))?
+    }
+
+    w.write_all(&(payload.len() as u32).to_be_bytes())?;
+    w.write_all(payload)?;
+    Ok(w.flush()?)
+}
+
+pub fn read_frame<R: Read>(r: &mut R) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut header = [0u8; 4];
+    r.read_exact(&mut header)?;
+
+    let mut payload = vec![0; frame_len(header)?];
+    r.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// Same as `write_frame` for an asynchronous stream
+#[cfg(feature = "tokio")]
+pub async fn write_frame_async<W: AsyncWrite + Unpin>(w: &mut W, payload: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if payload.len() > MAX_FRAME_SIZE as usize {
+        Err(format!("Message of {} bytes exceeds the maximum of {} bytes", payload.len(), MAX_FRAME_SIZE))?
+    }
+
+    w.write_all(&(payload.len() as u32).to_be_bytes()).await?;
+    w.write_all(payload).await?;
+    Ok(w.flush().await?)
+}
+
+/// Same as `read_frame` for an asynchronous stream
+#[cfg(feature = "tokio")]
+pub async fn read_frame_async<R: AsyncRead + Unpin>(r: &mut R) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut header = [0u8; 4];
+    r.read_exact(&mut header).await?;
+
+    let mut payload = vec![0; frame_len(header)?];
+    r.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+/// Read the length of a frame from its header
+fn frame_len(header: [u8; 4]) -> Result<usize, String> {
+    let len = u32::from_be_bytes(header);
+    if len > MAX_FRAME_SIZE {
+        Err(format!("Frame of {} bytes exceeds the maximum of {} bytes", len, MAX_FRAME_SIZE))?
+    }
+    Ok(len as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frame_round_trip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &serialize(&"hello").unwrap()).unwrap();
+
+        let payload = read_frame(&mut Cursor::new(buf)).unwrap();
+        let s: String = deserialize(&payload).unwrap();
+        assert_eq!(s, "hello");
+    }
+
+    #[test]
+    fn reject_oversized_frame() {
+        let mut buf = (MAX_FRAME_SIZE + 1).to_be_bytes().to_vec();
+        buf.extend_from_slice(&[0; 16]);
+
+        assert!(read_frame(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn reject_huge_string_length() {
+        // A small frame announcing a string of 2^60 bytes
+        let payload = (1u64 << 60).to_le_bytes();
+        assert!(deserialize::<String>(&payload).is_err());
+    }
+
+    #[test]
+    fn reject_truncated_frame() {
+        let mut buf = 8u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(&[0; 4]);
+
+        assert!(read_frame(&mut Cursor::new(buf)).is_err());
+    }
+
+    #[test]
+    fn reject_trailing_bytes() {
+        let mut payload = serialize(&1u32).unwrap();
+        payload.push(0);
+
+        assert!(deserialize::<u32>(&payload).is_err());
+    }
+}
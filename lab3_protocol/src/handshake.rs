@@ -0,0 +1,51 @@
+//! This file is used to define the hello exchanged when a client connects.
+//!
+//! The client announces its protocol version and the capabilities it supports. The server
+//! rejects a client speaking an unsupported version, otherwise both sides only use the
+//! capabilities supported by each of them.
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter};
+
+/// Version of the protocol defined by this crate, to increase on a change of the wire format
+/// which an older peer cannot ignore. New messages are announced with a capability instead.
+/// 1. Initial version
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest version still understood by this crate
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional groups of actions. The basic actions (users, phones, login) are always available.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Display, EnumIter)]
+pub enum Capability {
+    /// Show the history of a user and revert a phone number
+    History,
+    /// Backup and restore the database
+    Backup,
+    /// Query the audit log
+    Audit,
+    /// Receive a session token on login, resume a session, revoke the sessions of a user, list
+    /// and terminate the connected sessions
+    Sessions,
+    /// Receive a notice telling why the server closes the connection
+    Notices,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClientHello {
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ServerHello {
+    /// Version and capabilities used for the rest of the connection
+    Accepted { version: u32, capabilities: Vec<Capability> },
+    /// The client version is not supported, the server closes the connection
+    Rejected { min_version: u32, max_version: u32 },
+}
+
+/// Check if a peer speaking `version` can be served
+pub fn is_supported(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
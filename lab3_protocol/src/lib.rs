@@ -0,0 +1,17 @@
+//! This crate defines the messages exchanged between the client and the server, and how
+//! they are framed on the wire. Both binaries depend on it so that they cannot drift apart.
+
+mod audit;
+mod error;
+pub mod frame;
+mod handshake;
+mod message;
+mod session;
+mod user;
+
+pub use audit::{Event, EventInfo, Outcome, Page, Query};
+pub use error::{ProtocolError, Resource};
+pub use handshake::{is_supported, Capability, ClientHello, ServerHello, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+pub use message::{Expiry, Notice, Reply, Request, RequestMessage, Response, ServerMessage};
+pub use session::SessionInfo;
+pub use user::{Change, Field, UserInfo, UserRole};
@@ -0,0 +1,107 @@
+//! This file is used to define the requests sent by the client and the responses of the server.
+//!
+//! Every action is a single request carrying all its inputs, answered by a single response.
+//! The response carries the id of its request so that the client can detect a desynchronization.
+//! The server can also send a notice on its own initiative, right before closing the connection.
+
+use crate::audit::{Page, Query};
+use crate::error::ProtocolError;
+use crate::handshake::Capability;
+use crate::session::SessionInfo;
+use crate::user::{Change, UserInfo, UserRole};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Request {
+    ShowUsers,
+    ChangeOwnPhone { phone: String },
+    ChangePhone { username: String, phone: String },
+    AddUser { username: String, password: String, phone: String, role: UserRole },
+    Login { username: String, password: String },
+    Logout,
+    Backup,
+    Restore { snapshot: String },
+    ShowHistory { username: String },
+    RevertPhone { username: String, version: u32 },
+    QueryAudit { query: Query },
+    Exit,
+    /// Restore the session of a previous connection with its token, answered with a new token
+    Resume { token: String },
+    RevokeSessions { username: String },
+    ListSessions,
+    /// Close a connection, its client is told that its session was terminated
+    TerminateSession { id: u64 },
+}
+
+impl Request {
+    /// Capability which must be negotiated to send this request, if any
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            Request::ShowHistory { .. } | Request::RevertPhone { .. } => Some(Capability::History),
+            Request::Backup | Request::Restore { .. } => Some(Capability::Backup),
+            Request::QueryAudit { .. } => Some(Capability::Audit),
+            Request::Resume { .. }
+            | Request::RevokeSessions { .. }
+            | Request::ListSessions
+            | Request::TerminateSession { .. } => Some(Capability::Sessions),
+            _ => None,
+        }
+    }
+}
+
+/// Content of a successful response
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Reply {
+    /// The action was performed and has nothing to return
+    Done,
+    Users(Vec<UserInfo>),
+    /// Name of the created snapshot
+    Snapshot(String),
+    History(Vec<Change>),
+    AuditPage(Page),
+    /// Token of the session opened by a login or resumed
+    Session(String),
+    /// Number of sessions revoked
+    Revoked(u64),
+    Sessions(Vec<SessionInfo>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RequestMessage {
+    pub id: u64,
+    pub request: Request,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Response {
+    pub id: u64,
+    pub result: Result<Reply, ProtocolError>,
+}
+
+/// Why the server ended a session
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Expiry {
+    /// No request was received for too long
+    Idle,
+    /// The session reached its maximum duration since the login
+    Lifetime,
+}
+
+/// Message sent by the server on its own initiative, the connection is closed right after.
+/// It is only sent to the clients which negotiated `Capability::Notices`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Notice {
+    SessionExpired(Expiry),
+    /// The server is stopping and does not accept requests anymore
+    ShuttingDown,
+    /// The session was terminated by HR
+    Terminated,
+}
+
+/// Messages sent by the server once the hello is done
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ServerMessage {
+    Banner(String),
+    Response(Response),
+    Notice(Notice),
+}
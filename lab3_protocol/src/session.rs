@@ -0,0 +1,18 @@
+//! This file is used to describe the connections currently served by the server
+
+use serde::{Deserialize, Serialize};
+
+/// A connection to the server, sent by the `ListSessions` action.
+/// The times are Unix timestamps in seconds.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    /// Identifier used to terminate the connection
+    pub id: u64,
+    /// User logged in on the connection, if any
+    pub username: Option<String>,
+    /// Address of the client
+    pub peer: String,
+    pub connected_at: i64,
+    /// Time of the last request received
+    pub last_activity: i64,
+}
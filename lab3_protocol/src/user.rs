@@ -0,0 +1,49 @@
+//! This file is used to define the user related data sent between the client and the server
+
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter, EnumString};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Display, EnumString, EnumIter)]
+pub enum UserRole {
+    #[strum(serialize = "StandardUser")]
+    StandardUser,
+    #[strum(serialize = "HR")]
+    HR,
+    #[strum(serialize = "Admin")]
+    Admin,
+    #[strum(serialize = "Auditor")]
+    Auditor,
+}
+
+/// Public information of a user, sent by the `ShowUsers` action
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct UserInfo {
+    pub username: String,
+    pub phone_number: String,
+}
+
+impl UserInfo {
+    pub fn new(username: String, phone_number: String) -> Self {
+        Self {
+            username,
+            phone_number,
+        }
+    }
+}
+
+/// Fields of a user account that can be edited and are kept in its history
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Display)]
+pub enum Field {
+    #[strum(serialize = "phone number")]
+    PhoneNumber,
+}
+
+/// A single modification of a user account
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Change {
+    pub field: Field,
+    pub old_value: String,
+    pub new_value: String,
+    pub actor: String,
+    pub timestamp: i64,
+}
@@ -0,0 +1,271 @@
+//! Guard the wire format: a change in these bytes breaks the compatibility between clients
+//! and servers built from different versions. Update them only together with a protocol change.
+
+use lab3_protocol::frame::{deserialize, serialize};
+use lab3_protocol::{Capability, Change, ClientHello, Event, EventInfo, Expiry, Field, Notice, Outcome, Page, ProtocolError, Query, Reply, Request, RequestMessage, Resource, Response, ServerHello, ServerMessage, SessionInfo, UserInfo, UserRole};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Debug;
+
+/// Encoding of a string: u64 little-endian length followed by the UTF-8 bytes
+fn string(s: &str) -> Vec<u8> {
+    [(s.len() as u64).to_le_bytes().to_vec(), s.as_bytes().to_vec()].concat()
+}
+
+/// Encoding of an enum variant: u32 little-endian index
+fn variant(i: u32) -> Vec<u8> {
+    i.to_le_bytes().to_vec()
+}
+
+fn assert_wire<T: Serialize + DeserializeOwned + PartialEq + Debug>(o: &T, expected: Vec<u8>) {
+    assert_eq!(serialize(o).unwrap(), expected, "encoding of {:?} changed", o);
+    assert_eq!(&deserialize::<T>(&expected).unwrap(), o);
+}
+
+#[test]
+fn request_variants() {
+    let requests = [
+        (Request::ShowUsers, vec![]),
+        (Request::ChangeOwnPhone { phone: "0791234567".to_string() }, string("0791234567")),
+        (Request::ChangePhone { username: "alice".to_string(), phone: "0791234567".to_string() },
+         [string("alice"), string("0791234567")].concat()),
+        (Request::AddUser {
+            username: "alice".to_string(),
+            password: "Secret1.".to_string(),
+            phone: "0791234567".to_string(),
+            role: UserRole::HR,
+        }, [string("alice"), string("Secret1."), string("0791234567"), variant(1)].concat()),
+        (Request::Login { username: "alice".to_string(), password: "Secret1.".to_string() },
+         [string("alice"), string("Secret1.")].concat()),
+        (Request::Logout, vec![]),
+        (Request::Backup, vec![]),
+        (Request::Restore { snapshot: "db_1.ron".to_string() }, string("db_1.ron")),
+        (Request::ShowHistory { username: "alice".to_string() }, string("alice")),
+        (Request::RevertPhone { username: "alice".to_string(), version: 3 },
+         [string("alice"), 3u32.to_le_bytes().to_vec()].concat()),
+        (Request::QueryAudit { query: query() },
+         serialize(&query()).unwrap()),
+        (Request::Exit, vec![]),
+        (Request::Resume { token: "00ff".to_string() }, string("00ff")),
+        (Request::RevokeSessions { username: "alice".to_string() }, string("alice")),
+        (Request::ListSessions, vec![]),
+        (Request::TerminateSession { id: 7 }, 7u64.to_le_bytes().to_vec()),
+    ];
+    for (i, (r, fields)) in requests.into_iter().enumerate() {
+        assert_wire(&r, [variant(i as u32), fields].concat());
+    }
+}
+
+#[test]
+fn request_message() {
+    let message = RequestMessage { id: 42, request: Request::Logout };
+    assert_wire(&message, [42u64.to_le_bytes().to_vec(), variant(5)].concat());
+}
+
+#[test]
+fn response() {
+    let done = Response { id: 42, result: Ok(Reply::Done) };
+    assert_wire(&done, [42u64.to_le_bytes().to_vec(), variant(0), variant(0)].concat());
+
+    let snapshot = Response { id: 1, result: Ok(Reply::Snapshot("db_1.ron".to_string())) };
+    assert_wire(&snapshot, [1u64.to_le_bytes().to_vec(), variant(0), variant(2), string("db_1.ron")].concat());
+
+    let users = Response { id: 2, result: Ok(Reply::Users(vec![UserInfo::new("alice".to_string(), "0791234567".to_string())])) };
+    assert_wire(&users, [
+        2u64.to_le_bytes().to_vec(),
+        variant(0), variant(1),
+        1u64.to_le_bytes().to_vec(), string("alice"), string("0791234567"),
+    ].concat());
+
+    let session = Response { id: 4, result: Ok(Reply::Session("00ff".to_string())) };
+    assert_wire(&session, [4u64.to_le_bytes().to_vec(), variant(0), variant(5), string("00ff")].concat());
+
+    let revoked = Response { id: 5, result: Ok(Reply::Revoked(2)) };
+    assert_wire(&revoked, [5u64.to_le_bytes().to_vec(), variant(0), variant(6), 2u64.to_le_bytes().to_vec()].concat());
+
+    let sessions = Response { id: 6, result: Ok(Reply::Sessions(vec![session_info()])) };
+    assert_wire(&sessions, [
+        6u64.to_le_bytes().to_vec(),
+        variant(0), variant(7),
+        1u64.to_le_bytes().to_vec(), serialize(&session_info()).unwrap(),
+    ].concat());
+
+    let error = Response { id: 3, result: Err(ProtocolError::Forbidden) };
+    assert_wire(&error, [3u64.to_le_bytes().to_vec(), variant(1), variant(1)].concat());
+}
+
+#[test]
+fn protocol_errors() {
+    let errors = [
+        (ProtocolError::Unauthenticated, vec![]),
+        (ProtocolError::Forbidden, vec![]),
+        (ProtocolError::NotFound(Resource::Version), variant(1)),
+        (ProtocolError::AlreadyExists(Resource::Session), variant(3)),
+        (ProtocolError::validation("phone number", "0xxxxxxxxx"), [string("phone number"), string("0xxxxxxxxx")].concat()),
+        (ProtocolError::RateLimited, vec![]),
+        (ProtocolError::Unsupported, vec![]),
+        (ProtocolError::Internal, vec![]),
+    ];
+    for (i, (e, fields)) in errors.into_iter().enumerate() {
+        assert_wire(&e, [variant(i as u32), fields].concat());
+    }
+}
+
+#[test]
+fn user_role_variants() {
+    let roles = [UserRole::StandardUser, UserRole::HR, UserRole::Admin, UserRole::Auditor];
+    for (i, r) in roles.iter().enumerate() {
+        assert_wire(r, variant(i as u32));
+    }
+}
+
+#[test]
+fn audit_variants() {
+    let events = [
+        Event::Login,
+        Event::Logout,
+        Event::ChangeOwnPhone,
+        Event::ChangePhone,
+        Event::AddUser,
+        Event::Backup,
+        Event::Restore,
+        Event::ShowHistory,
+        Event::RevertPhone,
+        Event::QueryAudit,
+        Event::SessionExpired,
+        Event::Resume,
+        Event::RevokeSessions,
+        Event::TerminateSession,
+        Event::ListSessions,
+    ];
+    for (i, e) in events.iter().enumerate() {
+        assert_wire(e, variant(i as u32));
+    }
+
+    let outcomes = [Outcome::Success, Outcome::Failure, Outcome::Denied];
+    for (i, o) in outcomes.iter().enumerate() {
+        assert_wire(o, variant(i as u32));
+    }
+}
+
+#[test]
+fn user_info() {
+    let info = UserInfo::new("alice".to_string(), "0791234567".to_string());
+    assert_wire(&info, [string("alice"), string("0791234567")].concat());
+}
+
+#[test]
+fn change() {
+    let change = Change {
+        field: Field::PhoneNumber,
+        old_value: "0780000000".to_string(),
+        new_value: "0791234567".to_string(),
+        actor: "default_hr".to_string(),
+        timestamp: 1655128800,
+    };
+    assert_wire(&change, [
+        variant(0),
+        string("0780000000"),
+        string("0791234567"),
+        string("default_hr"),
+        1655128800i64.to_le_bytes().to_vec(),
+    ].concat());
+}
+
+fn query() -> Query {
+    Query {
+        actor: None,
+        target: Some("alice".to_string()),
+        event: Some(Event::Login),
+        outcome: None,
+        from: Some(10),
+        to: None,
+        page: 2,
+        page_size: 20,
+    }
+}
+
+#[test]
+fn audit_query() {
+    assert_wire(&query(), [
+        vec![0],
+        vec![1], string("alice"),
+        vec![1], variant(0),
+        vec![0],
+        vec![1], 10i64.to_le_bytes().to_vec(),
+        vec![0],
+        2u32.to_le_bytes().to_vec(),
+        20u32.to_le_bytes().to_vec(),
+    ].concat());
+}
+
+#[test]
+fn page() {
+    let page = Page {
+        events: vec![EventInfo {
+            seq: 7,
+            timestamp: 1655128800,
+            actor: Some("default_hr".to_string()),
+            event: Event::ChangePhone,
+            target: None,
+            outcome: Outcome::Denied,
+        }],
+        total: 1,
+    };
+    assert_wire(&page, [
+        1u64.to_le_bytes().to_vec(),
+        7u64.to_le_bytes().to_vec(),
+        1655128800i64.to_le_bytes().to_vec(),
+        vec![1], string("default_hr"),
+        variant(3),
+        vec![0],
+        variant(2),
+        1u64.to_le_bytes().to_vec(),
+    ].concat());
+}
+
+#[test]
+fn hello() {
+    let client = ClientHello { version: 1, capabilities: vec![Capability::History, Capability::Backup, Capability::Audit, Capability::Sessions, Capability::Notices] };
+    assert_wire(&client, [1u32.to_le_bytes().to_vec(), 5u64.to_le_bytes().to_vec(), variant(0), variant(1), variant(2), variant(3), variant(4)].concat());
+
+    let accepted = ServerHello::Accepted { version: 1, capabilities: vec![Capability::Audit] };
+    assert_wire(&accepted, [variant(0), 1u32.to_le_bytes().to_vec(), 1u64.to_le_bytes().to_vec(), variant(2)].concat());
+
+    let rejected = ServerHello::Rejected { min_version: 1, max_version: 2 };
+    assert_wire(&rejected, [variant(1), 1u32.to_le_bytes().to_vec(), 2u32.to_le_bytes().to_vec()].concat());
+}
+
+#[test]
+fn server_messages() {
+    assert_wire(&ServerMessage::Banner("Welcome".to_string()), [variant(0), string("Welcome")].concat());
+
+    let response = ServerMessage::Response(Response { id: 7, result: Ok(Reply::Done) });
+    assert_wire(&response, [variant(1), 7u64.to_le_bytes().to_vec(), variant(0), variant(0)].concat());
+
+    assert_wire(&ServerMessage::Notice(Notice::SessionExpired(Expiry::Idle)), [variant(2), variant(0), variant(0)].concat());
+    assert_wire(&ServerMessage::Notice(Notice::SessionExpired(Expiry::Lifetime)), [variant(2), variant(0), variant(1)].concat());
+    assert_wire(&ServerMessage::Notice(Notice::ShuttingDown), [variant(2), variant(1)].concat());
+    assert_wire(&ServerMessage::Notice(Notice::Terminated), [variant(2), variant(2)].concat());
+}
+
+fn session_info() -> SessionInfo {
+    SessionInfo {
+        id: 3,
+        username: Some("alice".to_string()),
+        peer: "127.0.0.1:50000".to_string(),
+        connected_at: 1655128800,
+        last_activity: 1655128860,
+    }
+}
+
+#[test]
+fn session() {
+    assert_wire(&session_info(), [
+        3u64.to_le_bytes().to_vec(),
+        vec![1], string("alice"),
+        string("127.0.0.1:50000"),
+        1655128800i64.to_le_bytes().to_vec(),
+        1655128860i64.to_le_bytes().to_vec(),
+    ].concat());
+}
@@ -1,219 +1,591 @@
-/// This file is used to execute the various actions submitted by the clients
+//! This file is used to execute the various actions submitted by the clients
 
 use crate::connection::Connection;
 use crate::database::Database;
-use crate::user::{UserAccount, UserInfo, UserRole};
-use serde::{Deserialize, Serialize};
+use crate::user::{Field, UserAccount, UserRole};
+use lab3_protocol::{Capability, Expiry, ProtocolError, Reply, Request, Resource};
 use std::error::Error;
+use std::io;
+use std::io::ErrorKind;
 use casbin::prelude::{CoreApi, Enforcer};
-use log::{info, warn};
-use strum_macros::{EnumIter, EnumString};
-use crate::argon2::{hash_password, verify_password};
-use crate::validator::{validate_password, validate_phone, validate_username};
-
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumIter)]
-pub enum Action {
-    #[strum(serialize = "Show users", serialize = "1")]
-    ShowUsers,
-    #[strum(serialize = "Change my phone number", serialize = "2")]
-    ChangeOwnPhone,
-    #[strum(serialize = "Show someone's phone number", serialize = "3")]
-    ChangePhone,
-    #[strum(serialize = "Add user", serialize = "4")]
-    AddUser,
-    #[strum(serialize = "Login", serialize = "5")]
-    Login,
-    #[strum(serialize = "Logout", serialize = "6")]
-    Logout,
-    #[strum(serialize = "Exit", serialize = "7")]
-    Exit,
-}
-
-/// The individual actions are implemented with three main steps:
-///     1. Read client inputs if required
-///     2. Execute various server code
-///     3. Send a result
-impl Action {
-    const FORBIDDEN_MSG: &'static str = "forbidden";
-    const UNAUTHENTICATED_MSG: &'static str = "unauthenticated";
-
-    pub fn perform(&self, u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        let res = match self {
-            Action::ShowUsers => Action::show_users(u),
-            Action::ChangeOwnPhone => Action::change_own_phone(u),
-            Action::ChangePhone => Action::change_phone(u),
-            Action::AddUser => Action::add_user(u),
-            Action::Login => Action::login(u),
-            Action::Logout => Action::logout(u),
-            Action::Exit => Err("Client disconnected")?,
-        };
+use log::{error, info, warn};
+use tokio::time::{Duration, Instant};
+use zeroize::Zeroizing;
+use crate::argon2::{hash_password, verify_dummy_password, verify_password};
+use crate::audit;
+use crate::config;
+use crate::registry;
+use crate::sessions;
+use crate::sessions::Session;
+use crate::audit::{Event, Outcome, Query};
+use crate::validator::{validate_password, validate_phone, validate_username, PASSWORD_RULE, PHONE_RULE, SNAPSHOT_RULE, USERNAME_RULE};
+
+/// Result of an action: the reply or the refusal sent to the client. An `Err` at the outer
+/// level is an internal error which closes the connection.
+pub type ActionResult = Result<Result<Reply, ProtocolError>, Box<dyn Error>>;
 
-        res
+/// The individual actions are implemented with two main steps:
+///     1. Execute various server code with the inputs carried by the request
+///     2. Return a reply or a refusal, which is sent back as the response
+pub async fn perform(request: Request, u: &mut ConnectedUser) -> ActionResult {
+    if let Some(capability) = request.capability() {
+        if !u.supports(capability) {
+            warn!("client sent a request requiring the capability {} which was not negotiated", capability);
+            return Ok(Err(ProtocolError::Unsupported));
+        }
     }
 
-    pub fn show_users(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        let users = Database::get_all_user_info()?;
-        let res: Result<Vec<UserInfo>, &str> = Ok(users);
-        u.conn().send(&res)
+    match request {
+        Request::ShowUsers => show_users().await,
+        Request::ChangeOwnPhone { phone } => change_own_phone(u, phone).await,
+        Request::ChangePhone { username, phone } => change_phone(u, username, phone).await,
+        Request::AddUser { username, password, phone, role } => add_user(u, username, Zeroizing::new(password), phone, role.into()).await,
+        Request::Login { username, password } => login(u, username, Zeroizing::new(password)).await,
+        Request::Logout => logout(u).await,
+        Request::Backup => backup(u).await,
+        Request::Restore { snapshot } => restore(u, snapshot).await,
+        Request::ShowHistory { username } => show_history(u, username).await,
+        Request::RevertPhone { username, version } => revert_phone(u, username, version).await,
+        Request::QueryAudit { query } => query_audit(u, query).await,
+        Request::Resume { token } => resume(u, token).await,
+        Request::RevokeSessions { username } => revoke_sessions(u, username).await,
+        Request::ListSessions => list_sessions(u).await,
+        Request::TerminateSession { id } => terminate_session(u, id).await,
+        Request::Exit => Err("Client disconnected")?,
     }
+}
 
-    pub fn change_own_phone(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        let phone = u.conn().receive::<String>()?;
+pub async fn show_users() -> ActionResult {
+    Ok(Ok(Reply::Users(Database::get_all_user_info()?)))
+}
 
-        // Control access and validate phone
-        let res = if u.is_anonymous() {
-            warn!("Access forbidden to anonymous user trying to change own phone");
-            Err(Action::UNAUTHENTICATED_MSG)
-        } else {
-            let mut user = u.user_account()?;
-            if !Action::control_access(&user.role().to_string(), "changeOwnPhone")? {
-                warn!("Access forbidden to \"{}\" trying to change own phone", u.username());
-                Err(Action::FORBIDDEN_MSG)
-            } else if !validate_phone(&phone) {
-                warn!("\"{}\" try to change own phone with an invalid number", u.username());
-                Err("Invalid phone number")
-            } else {
-                user.set_phone_number(phone);
-                Database::insert(&user)?;
-                info!("\"{}\" changed own phone", u.username());
-                Ok(())
+pub async fn change_own_phone(u: &mut ConnectedUser, phone: String) -> ActionResult {
+    // Control access and validate phone
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to change own phone");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "changeOwnPhone").await? {
+        warn!("Access forbidden to \"{}\" trying to change own phone", u.username());
+        Err(ProtocolError::Forbidden)
+    } else if !validate_phone(&phone) {
+        warn!("\"{}\" try to change own phone with an invalid number", u.username());
+        Err(ProtocolError::validation("phone number", PHONE_RULE))
+    } else {
+        let username = u.username();
+        let actor = username.clone();
+        let changed = Database::update(&username, move |user| {
+            user.set_phone_number(phone, &actor);
+            Ok(Reply::Done)
+        }).await?;
+
+        // The account may have disappeared if a snapshot was restored in the meantime
+        let reply = changed.ok_or("User logged in but not in DB")?;
+        info!("\"{}\" changed own phone", username);
+        reply
+    };
+
+    audit(u.actor(), Event::ChangeOwnPhone, None, &res);
+    Ok(res)
+}
+
+pub async fn change_phone(u: &mut ConnectedUser, username: String, phone: String) -> ActionResult {
+    let username = username.to_lowercase();
+
+    // Control access, then read and change the account in one step
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to change phone");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "changePhone").await? {
+        warn!("Access forbidden to \"{}\" trying to change phone of \"{}\"", u.username(), &username);
+        Err(ProtocolError::Forbidden)
+    } else {
+        let actor = u.username();
+        let changed_by = actor.clone();
+        let changed = Database::update(&username, move |target_user| {
+            if !validate_phone(&phone) {
+                return Err(ProtocolError::validation("phone number", PHONE_RULE));
             }
-        };
+            target_user.set_phone_number(phone, &changed_by);
+            Ok(Reply::Done)
+        }).await?;
 
-        u.conn().send(&res)
-    }
-
-    pub fn change_phone(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        // Receive data
-        let username = u.conn().receive::<String>()?.to_lowercase();
-        let phone = u.conn().receive::<String>()?;
-        let target_user = Database::get(&username)?;
-
-        // Control access
-        let res = if u.is_anonymous() {
-            warn!("Access forbidden to anonymous user trying to change phone");
-            Err(Action::UNAUTHENTICATED_MSG)
-        } else if !Action::control_access(&u.user_account()?.role().to_string(), "changePhone")? {
-            warn!("Access forbidden to \"{}\" trying to change phone of \"{}\"", u.username(), &username);
-            Err(Action::FORBIDDEN_MSG)
-        } else if target_user.is_none() {
-            warn!("\"{}\" try to change phone, \"{}\" does not exist", u.username(), &username);
-            Err("Target user not found")
-        } else if !validate_phone(&phone) {
-            warn!("\"{}\" try to change phone of \"{}\" with an invalid number", u.username(), &username);
-            Err("Invalid phone number")
-        } else {
-            let mut target_user = target_user.unwrap();
-            target_user.set_phone_number(phone);
-            Database::insert(&target_user)?;
-            info!("\"{}\" changed phone of \"{}\"", u.username(), &username);
-            Ok(())
-        };
+        match changed {
+            Some(Ok(reply)) => {
+                info!("\"{}\" changed phone of \"{}\"", actor, &username);
+                Ok(reply)
+            }
+            Some(Err(e)) => {
+                warn!("\"{}\" try to change phone of \"{}\" with an invalid number", actor, &username);
+                Err(e)
+            }
+            None => {
+                warn!("\"{}\" try to change phone, \"{}\" does not exist", actor, &username);
+                Err(ProtocolError::NotFound(Resource::User))
+            }
+        }
+    };
 
-        u.conn().send(&res)
-    }
+    audit(u.actor(), Event::ChangePhone, Some(&username), &res);
+    Ok(res)
+}
 
-    pub fn add_user(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        // Receive data
-        let username = u.conn().receive::<String>()?.to_lowercase();
-        let password = u.conn().receive::<String>()?;
-        let phone = u.conn().receive::<String>()?;
-        let role = u.conn().receive::<UserRole>()?;
+/// The password is erased from memory once hashed
+pub async fn add_user(u: &mut ConnectedUser, username: String, password: Zeroizing<String>, phone: String, role: UserRole) -> ActionResult {
+    let username = username.to_lowercase();
 
-        // Control access and validate inputs
-        let res = if u.is_anonymous() {
-            warn!("Access forbidden to anonymous user trying to add a user");
-            Err(Action::UNAUTHENTICATED_MSG)
-        } else if !Action::control_access(&u.user_account()?.role().to_string(), "addUser")? {
-            warn!("Access forbidden to \"{}\" trying to add a user", u.username());
-            Err(Action::FORBIDDEN_MSG)
-        } else if Database::get(&username)?.is_some() {
-            warn!("\"{}\" try to add the user \"{}\", but it already exists", u.username(), &username);
-            Err("User already exists")
-        } else if !validate_username(&username) {
-            warn!("\"{}\" try to add an user with an invalid username", u.username());
-            Err("Invalid username")
-        } else if !validate_password(&password) {
-            warn!("\"{}\" try to add an user with an invalid password", u.username());
-            Err("Invalid password")
-        } else if !validate_phone(&phone) {
-            warn!("\"{}\" try to add user with an invalid phone number", u.username());
-            Err("Invalid phone number")
-        } else {
-            let user = UserAccount::new(username.clone(), hash_password(&password)?, phone, role);
-            Database::insert(&user)?;
+    // Control access and validate inputs
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to add a user");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "addUser").await? {
+        warn!("Access forbidden to \"{}\" trying to add a user", u.username());
+        Err(ProtocolError::Forbidden)
+    } else if Database::get(&username)?.is_some() {
+        warn!("\"{}\" try to add the user \"{}\", but it already exists", u.username(), &username);
+        Err(ProtocolError::AlreadyExists(Resource::User))
+    } else if !validate_username(&username) {
+        warn!("\"{}\" try to add an user with an invalid username", u.username());
+        Err(ProtocolError::validation("username", USERNAME_RULE))
+    } else if !validate_password(&password) {
+        warn!("\"{}\" try to add an user with an invalid password", u.username());
+        Err(ProtocolError::validation("password", PASSWORD_RULE))
+    } else if !validate_phone(&phone) {
+        warn!("\"{}\" try to add user with an invalid phone number", u.username());
+        Err(ProtocolError::validation("phone number", PHONE_RULE))
+    } else {
+        // Another client may have added the same user while the password was hashed
+        let user = UserAccount::new(username.clone(), hash_password(&password).await?, phone, role);
+        if Database::insert_new(user).await? {
             info!("\"{}\" user added by \"{}\"", &username, u.username());
-            Ok(())
-        };
+            Ok(Reply::Done)
+        } else {
+            warn!("\"{}\" try to add the user \"{}\", but it already exists", u.username(), &username);
+            Err(ProtocolError::AlreadyExists(Resource::User))
+        }
+    };
 
-        u.conn.send(&res)
-    }
+    audit(u.actor(), Event::AddUser, Some(&username), &res);
+    Ok(res)
+}
 
-    pub fn login(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        // Receive data
-        let username = u.conn().receive::<String>()?.to_lowercase();
-        let password = u.conn().receive::<String>()?;
+/// The password is erased from memory once verified
+pub async fn login(u: &mut ConnectedUser, username: String, password: Zeroizing<String>) -> ActionResult {
+    let username = username.to_lowercase();
 
-        let res = if !u.is_anonymous() {
-            Err("You are already logged in")
-        } else {
-            let user = Database::get(&username)?;
+    let res = if !u.is_anonymous() {
+        Err(ProtocolError::AlreadyExists(Resource::Session))
+    } else {
+        let user = Database::get(&username)?;
 
-            if let Some(user) = user {
-                if verify_password(&password, user.password())? {
-                    u.set_username(&username);
-                    info!("user \"{}\" logged in", u.username());
-                    Ok(())
+        if let Some(user) = user {
+            if verify_password(&password, user.password()).await? {
+                let token = u.start_session(&username, Authentication::Password);
+                info!("user \"{}\" logged in", u.username());
+                if u.is_resumable() {
+                    Ok(Reply::Session(token))
                 } else {
-                    warn!("user \"{}\" failed logging in: invalid credentials", username);
-                    Err("Authentication failed")
+                    Ok(Reply::Done)
                 }
             } else {
-                // we verify the password for timing reasons
-                verify_password("Fail", "$argon2id$v=19$m=65536,t=3,p=4$0000000000000000000000$00000000000000000000000000000000000000000000000000000000000000000000000000000000000000")?;
-                warn!("user \"{}\" failed logging in: invalid user", username);
-                Err("Authentication failed")
+                warn!("user \"{}\" failed logging in: invalid credentials", username);
+                Err(ProtocolError::Unauthenticated)
             }
-        };
+        } else {
+            // we verify the password for timing reasons
+            verify_dummy_password(&password).await?;
+            warn!("user \"{}\" failed logging in: invalid user", username);
+            Err(ProtocolError::Unauthenticated)
+        }
+    };
+
+    audit(u.actor(), Event::Login, Some(&username), &res);
+    Ok(res)
+}
 
-        u.conn.send(&res)
+/// Log in the user named by the client certificate, the first name of an existing account is used
+pub fn certificate_login(u: &mut ConnectedUser, names: &[String]) -> Result<(), Box<dyn Error>> {
+    for name in names {
+        let username = name.to_lowercase();
+        if Database::get(&username)?.is_some() {
+            // The token is not given, a client with a certificate logs in again when reconnecting
+            u.start_session(&username, Authentication::Certificate);
+            info!("user \"{}\" logged in with a client certificate", username);
+            audit(u.actor(), Event::Login, Some(&username), &Ok::<_, ProtocolError>(()));
+            return Ok(());
+        }
     }
 
-    pub fn logout(u: &mut ConnectedUser) -> Result<(), Box<dyn Error>> {
-        let res: Result<(), &str>;
+    if let Some(name) = names.first() {
+        warn!("client certificate of \"{}\" does not match any user", name);
+        audit(None, Event::Login, Some(name), &Err::<(), _>(ProtocolError::Unauthenticated));
+    }
+    Ok(())
+}
 
-        // Check permissions
-        res = if u.is_anonymous() {
-            Err("You are not logged in")
-        } else {
-            info!("user \"{}\" logged out", u.username());
-            u.logout();
-            Ok(())
+pub async fn logout(u: &mut ConnectedUser) -> ActionResult {
+    // Check permissions
+    let actor = u.actor().map(str::to_string);
+    let res = if u.is_anonymous() {
+        Err(ProtocolError::Unauthenticated)
+    } else {
+        info!("user \"{}\" logged out", u.username());
+        u.logout();
+        Ok(Reply::Done)
+    };
+
+    audit(actor.as_deref(), Event::Logout, None, &res);
+    Ok(res)
+}
+
+pub async fn backup(u: &mut ConnectedUser) -> ActionResult {
+    // Control access
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to backup the database");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "backup").await? {
+        warn!("Access forbidden to \"{}\" trying to backup the database", u.username());
+        Err(ProtocolError::Forbidden)
+    } else {
+        match Database::backup().await {
+            Ok(name) => {
+                info!("\"{}\" created the snapshot \"{}\"", u.username(), &name);
+                Ok(Reply::Snapshot(name))
+            }
+            Err(e) => {
+                error!("\"{}\" failed to backup the database: {}", u.username(), e);
+                Err(ProtocolError::Internal)
+            }
+        }
+    };
+
+    audit(u.actor(), Event::Backup, None, &res);
+    Ok(res)
+}
+
+pub async fn restore(u: &mut ConnectedUser, name: String) -> ActionResult {
+    // Control access and validate the snapshot
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to restore the database");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "restore").await? {
+        warn!("Access forbidden to \"{}\" trying to restore the database", u.username());
+        Err(ProtocolError::Forbidden)
+    } else {
+        let snapshot = match Database::read_snapshot(&name).await {
+            Ok(snapshot) => Ok(snapshot),
+            Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == ErrorKind::NotFound) => {
+                warn!("\"{}\" try to restore the unknown snapshot \"{}\"", u.username(), &name);
+                Err(ProtocolError::NotFound(Resource::Snapshot))
+            }
+            Err(e) => {
+                warn!("\"{}\" try to restore an invalid snapshot: {}", u.username(), e);
+                Err(ProtocolError::validation("snapshot", SNAPSHOT_RULE))
+            }
         };
 
-        u.conn.send(&res)
+        match snapshot {
+            Ok(snapshot) => match Database::restore(snapshot).await {
+                Ok(()) => {
+                    info!("\"{}\" restored the snapshot \"{}\"", u.username(), &name);
+                    Ok(Reply::Done)
+                }
+                Err(e) => {
+                    error!("\"{}\" failed to restore the snapshot \"{}\": {}", u.username(), &name, e);
+                    Err(ProtocolError::Internal)
+                }
+            },
+            Err(e) => Err(e),
+        }
+    };
+
+    audit(u.actor(), Event::Restore, Some(&name), &res);
+    Ok(res)
+}
+
+pub async fn show_history(u: &mut ConnectedUser, username: String) -> ActionResult {
+    let username = username.to_lowercase();
+    let target_user = Database::get(&username)?;
+
+    // Control access
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to show history");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "showHistory").await? {
+        warn!("Access forbidden to \"{}\" trying to show history of \"{}\"", u.username(), &username);
+        Err(ProtocolError::Forbidden)
+    } else if let Some(target_user) = target_user {
+        info!("\"{}\" consulted history of \"{}\"", u.username(), &username);
+        Ok(Reply::History(target_user.history().iter().cloned().map(Into::into).collect()))
+    } else {
+        warn!("\"{}\" try to show history, \"{}\" does not exist", u.username(), &username);
+        Err(ProtocolError::NotFound(Resource::User))
+    };
+
+    audit(u.actor(), Event::ShowHistory, Some(&username), &res);
+    Ok(res)
+}
+
+pub async fn revert_phone(u: &mut ConnectedUser, username: String, version: u32) -> ActionResult {
+    let username = username.to_lowercase();
+
+    // Control access, then read the version and change the account in one step. The reverted
+    // value is validated like a normal phone change.
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to revert phone");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "changePhone").await? {
+        warn!("Access forbidden to \"{}\" trying to revert phone of \"{}\"", u.username(), &username);
+        Err(ProtocolError::Forbidden)
+    } else {
+        let actor = u.username();
+        let changed_by = actor.clone();
+        let reverted = Database::update(&username, move |target_user| {
+            match target_user.value_at(Field::PhoneNumber, version as usize) {
+                None => Err(ProtocolError::NotFound(Resource::Version)),
+                Some(phone) if !validate_phone(&phone) => Err(ProtocolError::validation("phone number", PHONE_RULE)),
+                Some(phone) => {
+                    target_user.set_phone_number(phone, &changed_by);
+                    Ok(Reply::Done)
+                }
+            }
+        }).await?;
+
+        match reverted {
+            Some(Ok(reply)) => {
+                info!("\"{}\" reverted phone of \"{}\" to version {}", actor, &username, version);
+                Ok(reply)
+            }
+            Some(Err(e @ ProtocolError::NotFound(_))) => {
+                warn!("\"{}\" try to revert phone of \"{}\" to unknown version {}", actor, &username, version);
+                Err(e)
+            }
+            Some(Err(e)) => {
+                warn!("\"{}\" try to revert phone of \"{}\" to an invalid number", actor, &username);
+                Err(e)
+            }
+            None => {
+                warn!("\"{}\" try to revert phone, \"{}\" does not exist", actor, &username);
+                Err(ProtocolError::NotFound(Resource::User))
+            }
+        }
+    };
+
+    audit(u.actor(), Event::RevertPhone, Some(&username), &res);
+    Ok(res)
+}
+
+pub async fn query_audit(u: &mut ConnectedUser, query: Query) -> ActionResult {
+    // Control access
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to query the audit log");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "queryAudit").await? {
+        warn!("Access forbidden to \"{}\" trying to query the audit log", u.username());
+        Err(ProtocolError::Forbidden)
+    } else if query.page_size == 0 || query.page_size > audit::MAX_PAGE_SIZE {
+        warn!("\"{}\" try to query the audit log with an invalid page size", u.username());
+        Err(ProtocolError::validation("page size", &format!("must be between 1 and {}", audit::MAX_PAGE_SIZE)))
+    } else {
+        match audit::query(query.clone()).await {
+            Ok(page) => {
+                info!("\"{}\" queried the audit log", u.username());
+                Ok(Reply::AuditPage(page))
+            }
+            Err(e) => {
+                error!("\"{}\" failed to query the audit log: {}", u.username(), e);
+                Err(ProtocolError::Internal)
+            }
+        }
+    };
+
+    audit(u.actor(), Event::QueryAudit, query.target.as_deref(), &res);
+    Ok(res)
+}
+
+pub async fn resume(u: &mut ConnectedUser, token: String) -> ActionResult {
+    let res = if !u.is_anonymous() {
+        Err(ProtocolError::AlreadyExists(Resource::Session))
+    } else if let Some((token, id, session)) = sessions::resume(&token) {
+        u.resume_session(id, session);
+        info!("user \"{}\" resumed a session", u.username());
+        // The token given with the request cannot be used again
+        Ok(Reply::Session(token))
+    } else {
+        warn!("failed to resume a session: invalid or expired token");
+        Err(ProtocolError::Unauthenticated)
+    };
+
+    audit(u.actor(), Event::Resume, u.actor(), &res);
+    Ok(res)
+}
+
+pub async fn revoke_sessions(u: &mut ConnectedUser, username: String) -> ActionResult {
+    let username = username.to_lowercase();
+
+    // Control access
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to revoke sessions");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "revokeSessions").await? {
+        warn!("Access forbidden to \"{}\" trying to revoke sessions of \"{}\"", u.username(), &username);
+        Err(ProtocolError::Forbidden)
+    } else if Database::get(&username)?.is_none() {
+        warn!("\"{}\" try to revoke sessions, \"{}\" does not exist", u.username(), &username);
+        Err(ProtocolError::NotFound(Resource::User))
+    } else {
+        let count = sessions::revoke_user(&username);
+        info!("\"{}\" revoked {} sessions of \"{}\"", u.username(), count, &username);
+        Ok(Reply::Revoked(count))
+    };
+
+    audit(u.actor(), Event::RevokeSessions, Some(&username), &res);
+    Ok(res)
+}
+
+pub async fn list_sessions(u: &mut ConnectedUser) -> ActionResult {
+    // Control access
+    let res = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to list sessions");
+        Err(ProtocolError::Unauthenticated)
+    } else if !allowed(u, "listSessions").await? {
+        warn!("Access forbidden to \"{}\" trying to list sessions", u.username());
+        Err(ProtocolError::Forbidden)
+    } else {
+        info!("\"{}\" listed the connected sessions", u.username());
+        Ok(Reply::Sessions(registry::list()))
+    };
+
+    audit(u.actor(), Event::ListSessions, None, &res);
+    Ok(res)
+}
+
+pub async fn terminate_session(u: &mut ConnectedUser, id: u64) -> ActionResult {
+    // Control access
+    let (res, target) = if u.is_anonymous() {
+        warn!("Access forbidden to anonymous user trying to terminate a session");
+        (Err(ProtocolError::Unauthenticated), None)
+    } else if !allowed(u, "terminateSession").await? {
+        warn!("Access forbidden to \"{}\" trying to terminate session {}", u.username(), id);
+        (Err(ProtocolError::Forbidden), None)
+    } else {
+        match registry::terminate(id) {
+            Some(target) => {
+                info!("\"{}\" terminated session {}", u.username(), id);
+                (Ok(Reply::Done), target)
+            }
+            None => {
+                warn!("\"{}\" try to terminate session {}, it does not exist", u.username(), id);
+                (Err(ProtocolError::NotFound(Resource::Session)), None)
+            }
+        }
+    };
+
+    audit(u.actor(), Event::TerminateSession, target.as_deref(), &res);
+    Ok(res)
+}
+
+/// End the session of a client which stayed idle for too long or reached the session lifetime
+pub fn expire(u: &mut ConnectedUser, expiry: Expiry) {
+    let reason = match expiry {
+        Expiry::Idle => "after being idle",
+        Expiry::Lifetime => "at the end of its lifetime",
+    };
+
+    if let Some(actor) = u.actor().map(str::to_string) {
+        info!("session of \"{}\" expired {}", actor, reason);
+        u.logout();
+        audit::record(Some(&actor), Event::SessionExpired, None, Outcome::Success);
+    } else {
+        info!("anonymous session expired {}", reason);
     }
+}
 
-    #[tokio::main]
-    async fn control_access(role: &str, resource: &str) -> casbin::Result<bool> {
-        let e = Enforcer::new("access_policy/model.conf", "access_policy/policy.csv")
-            .await
-            .expect("cannot read model or policy");
-        e.enforce((role, resource))
+/// Close the session of a client leaving. Only a client which lost its connection and holds the
+/// token of its session may resume it later.
+pub fn leave(u: &mut ConnectedUser, exited: bool) {
+    if exited || !u.is_resumable() {
+        if let Some(actor) = u.actor().map(str::to_string) {
+            info!("user \"{}\" logged out when leaving", actor);
+            u.logout();
+            audit::record(Some(&actor), Event::Logout, None, Outcome::Success);
+        }
+    }
+}
+
+/// Record the result of an action in the audit log. Invalid credentials on login are a
+/// failure, not a refusal of the access control.
+fn audit<T>(actor: Option<&str>, event: Event, target: Option<&str>, res: &Result<T, ProtocolError>) {
+    let outcome = match res {
+        Ok(_) => Outcome::Success,
+        Err(ProtocolError::Forbidden) => Outcome::Denied,
+        Err(ProtocolError::Unauthenticated) if event != Event::Login => Outcome::Denied,
+        Err(_) => Outcome::Failure,
+    };
+    audit::record(actor, event, target, outcome);
+}
+
+/// Check if the role of a logged in user is allowed to access a resource with its session
+async fn allowed(u: &mut ConnectedUser, resource: &str) -> Result<bool, Box<dyn Error>> {
+    let role = u.user_account()?.role().to_string();
+    Ok(control_access(&role, resource, u.authentication()).await?)
+}
+
+/// The policy can require a session authenticated with a certificate for a resource
+async fn control_access(role: &str, resource: &str, authentication: Option<Authentication>) -> casbin::Result<bool> {
+    let access = &config::get().access;
+    enforce(&access.model, &access.policy, role, resource, authentication).await
+}
+
+async fn enforce(model: &'static str, policy: &'static str, role: &str, resource: &str, authentication: Option<Authentication>) -> casbin::Result<bool> {
+    let e = Enforcer::new(model, policy)
+        .await
+        .expect("cannot read model or policy");
+    e.enforce((role, resource, authentication.map_or("none", Authentication::policy_name)))
+}
+
+/// How the user of a session proved its identity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Authentication {
+    Password,
+    Certificate,
+}
+
+impl Authentication {
+    /// Name of the authentication in the access policy
+    fn policy_name(self) -> &'static str {
+        match self {
+            Authentication::Password => "password",
+            Authentication::Certificate => "certificate",
+        }
     }
 }
 
 /// Used to represent a connected user for the actions
 pub struct ConnectedUser {
     username: Option<String>,
+    /// Id of the session opened by the login
+    session: Option<String>,
+    expires_at: Option<Instant>,
+    session_lifetime: Duration,
     conn: Connection,
+    /// Capabilities negotiated during the hello
+    capabilities: Vec<Capability>,
+    authentication: Option<Authentication>,
+    /// Whether the client was given the token of its session
+    resumable: bool,
 }
 
 impl ConnectedUser {
-    pub fn anonymous(conn: Connection) -> ConnectedUser {
+    pub fn anonymous(conn: Connection, capabilities: Vec<Capability>, session_lifetime: Duration) -> ConnectedUser {
         ConnectedUser {
             username: None,
+            session: None,
+            expires_at: None,
+            session_lifetime,
             conn,
+            capabilities,
+            authentication: None,
+            resumable: false,
         }
     }
 
@@ -225,19 +597,106 @@ impl ConnectedUser {
         &mut self.conn
     }
 
-    pub fn set_username(&mut self, username: &str) {
+    /// Check that a capability was negotiated during the hello
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Log in a user and open its session, returns the token of the session
+    pub fn start_session(&mut self, username: &str, authentication: Authentication) -> String {
+        let expires_at = Instant::now() + self.session_lifetime;
+        let (token, id) = sessions::open(username, expires_at);
         self.username = Some(username.to_string());
+        self.authentication = Some(authentication);
+        self.session = Some(id);
+        self.expires_at = Some(expires_at);
+        // Older clients do not know about the session tokens
+        self.resumable = authentication == Authentication::Password && self.supports(Capability::Sessions);
+        token
+    }
+
+    /// Continue a session opened on a previous connection, only password logins give a token
+    pub fn resume_session(&mut self, id: String, session: Session) {
+        self.username = Some(session.username);
+        self.authentication = Some(Authentication::Password);
+        self.session = Some(id);
+        self.expires_at = Some(session.expires_at);
+        self.resumable = true;
+    }
+
+    /// Whether the client can resume the session after losing its connection
+    pub fn is_resumable(&self) -> bool {
+        self.resumable
+    }
+
+    /// End of the session lifetime, if logged in
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.expires_at
+    }
+
+    /// Check that the session was not revoked in the meantime
+    pub fn is_session_open(&self) -> bool {
+        self.session.as_deref().is_some_and(sessions::is_open)
+    }
+
+    pub fn actor(&self) -> Option<&str> {
+        self.username.as_deref()
     }
 
     pub fn is_anonymous(&self) -> bool {
-        return self.username.is_none();
+        self.username.is_none()
     }
 
     pub fn logout(&mut self) {
+        if let Some(id) = self.session.take() {
+            sessions::close(&id);
+        }
         self.username = None;
+        self.expires_at = None;
+        self.authentication = None;
+        self.resumable = false;
+    }
+
+    pub fn authentication(&self) -> Option<Authentication> {
+        self.authentication
     }
 
     pub fn user_account(&mut self) -> Result<UserAccount, Box<dyn Error>> {
-        Ok(Database::get(&self.username())?.expect("User logged in but not in DB"))
+        // The account may have disappeared if a snapshot was restored in the meantime
+        Ok(Database::get(&self.username())?.ok_or("User logged in but not in DB")?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn admin_is_only_given_the_snapshots() {
+        for authentication in [Authentication::Password, Authentication::Certificate] {
+            assert!(control_access("Admin", "backup", Some(authentication)).await.unwrap());
+            assert!(control_access("Admin", "restore", Some(authentication)).await.unwrap());
+        }
+
+        // An admin is not given the permissions of HR
+        assert!(!control_access("Admin", "addUser", Some(Authentication::Certificate)).await.unwrap());
+        assert!(!control_access("Admin", "changePhone", Some(Authentication::Password)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn policy_can_require_a_certificate() {
+        let policy = std::env::temp_dir().join(format!("lab3_policy_{}.csv", std::process::id()));
+        std::fs::write(&policy, "p, Admin, backup, any\np, Admin, restore, certificate\n").unwrap();
+        let model = config::get().access.model.as_str();
+        // The enforcer only reads the files of static paths
+        let policy: &'static str = Box::leak(policy.to_str().unwrap().into());
+
+        let restore = |authentication| enforce(model, policy, "Admin", "restore", authentication);
+        assert!(restore(Some(Authentication::Certificate)).await.unwrap());
+        assert!(!restore(Some(Authentication::Password)).await.unwrap());
+        assert!(!restore(None).await.unwrap());
+        assert!(enforce(model, policy, "Admin", "backup", Some(Authentication::Password)).await.unwrap());
+
+        std::fs::remove_file(policy).unwrap();
     }
 }
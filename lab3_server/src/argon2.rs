@@ -3,34 +3,89 @@ use argon2::{Algorithm, Version};
 use argon2::{password_hash::{
     rand_core::OsRng,
     PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
-}, Argon2, Params};
+}, Argon2};
+use crate::config;
 use lazy_static::lazy_static;
+use rand::distributions::{Alphanumeric, DistString};
+use std::sync::OnceLock;
+use std::thread;
+use tokio::sync::Semaphore;
+use tokio::task;
+use zeroize::Zeroizing;
 
 
+/// # Config
+/// - Algorithm chosen: **Argon2id**
+/// - Version chosen: **19**
+/// - Memory cost, passes, lanes and output length: from the `argon2` section of the
+///   configuration, 64 MiB, 3, 4 and 64 B by default
+static ARGON2: OnceLock<Argon2<'static>> = OnceLock::new();
+
+/// Hash of a random password with the parameters of the server config, verified when a login
+/// names an unknown user so that it takes as long as for an existing one
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
 lazy_static! {
-    /// # Config
-    /// - Algorithm chosen: **Argon2id**
-    /// - Version chosen: **19**
-    /// - Memory cost chosen: **64 KiB**
-    /// - Number of passes chosen: **3**
-    /// - Number of lanes chosen: **4**
-    /// - Output length chosen: **64 B**
-    static ref ARGON2: Argon2<'static> = Argon2::new(
-        Algorithm::Argon2id,
-        Version::V0x13,
-        Params::new(65536, 3, 4, Some(64)).unwrap()
+    /// Hashing is done on the blocking pool, at most one per core at a time so that a burst of
+    /// logins cannot take the threads serving the other clients
+    static ref HASHING_SLOTS: Semaphore = Semaphore::new(
+        thread::available_parallelism().map_or(1, |n| n.get())
     );
 }
 
-/// Hash a password with Argon 2id based on the server config
+/// Set up Argon2 with the parameters of the server config, before any password is hashed
+///
+/// # Error
+/// If the parameters are invalid.
+pub fn init() -> Result<(), Box<dyn Error>> {
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, config::get().argon2_params()?);
+    ARGON2.set(argon2).map_err(|_| "Argon2 already set up")?;
+
+    let password = Zeroizing::new(Alphanumeric.sample_string(&mut OsRng, 32));
+    DUMMY_HASH.set(hash_password_now(&password)?).map_err(|_| "Argon2 already set up")?;
+    Ok(())
+}
+
+/// Run a costly operation on the bounded blocking pool
+async fn run_bounded<T, F>(f: F) -> Result<T, Box<dyn Error>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let _slot = HASHING_SLOTS.acquire().await?;
+    Ok(task::spawn_blocking(f).await?)
+}
+
+/// Hash a password with Argon 2id based on the server config, the copy given to the hashing
+/// thread is erased once hashed
 ///
 /// # Error
 /// If the hashing failed.
-pub fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
+pub async fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
+    let password = Zeroizing::new(password.to_string());
+
     // Hash password
-    match ARGON2.hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng)) {
-        Ok(h) => Ok(h.to_string()),
-        Err(_) => Err("Hashing password failed")?
+    let hash = run_bounded(move || hash(&password)).await?;
+    Ok(hash.ok_or("Hashing password failed")?)
+}
+
+/// Hash a password on the current thread, used at startup before serving the clients
+///
+/// # Error
+/// If the hashing failed.
+pub fn hash_password_now(password: &str) -> Result<String, Box<dyn Error>> {
+    Ok(hash(password).ok_or("Hashing password failed")?)
+}
+
+fn hash(password: &str) -> Option<String> {
+    ARGON2.get()?.hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng)).map(|h| h.to_string()).ok()
+}
+
+/// Check that a stored hash can be parsed as a PHC string produced by the server config
+pub fn validate_hash(hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(pwd_hash) => pwd_hash.algorithm == Algorithm::Argon2id.ident(),
+        Err(_) => false
     }
 }
 
@@ -38,9 +93,22 @@ pub fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
 ///
 /// # Error
 /// If the parsing of the hash failed
-pub fn verify_password(password: &str, hash: &str) -> Result<bool, Box<dyn Error>> {
-    match PasswordHash::new(hash) {
-        Ok(pwd_hash) => Ok(ARGON2.verify_password(password.as_ref(), &pwd_hash).is_ok()),
-        Err(_) => Err("Failed to parse hash")?
-    }
+pub async fn verify_password(password: &str, hash: &str) -> Result<bool, Box<dyn Error>> {
+    let (password, hash) = (Zeroizing::new(password.to_string()), hash.to_string());
+
+    let valid = run_bounded(move || match PasswordHash::new(&hash) {
+        Ok(pwd_hash) => Ok(ARGON2.get().ok_or("Argon2 not set up")?.verify_password(password.as_bytes(), &pwd_hash).is_ok()),
+        Err(_) => Err("Failed to parse hash")
+    }).await?;
+
+    Ok(valid?)
+}
+
+/// Verify a password against the dummy hash, for the timing of a login with an unknown user
+///
+/// # Error
+/// If Argon2 is not set up.
+pub async fn verify_dummy_password(password: &str) -> Result<(), Box<dyn Error>> {
+    verify_password(password, DUMMY_HASH.get().ok_or("Argon2 not set up")?).await?;
+    Ok(())
 }
@@ -0,0 +1,456 @@
+//! This file is used to keep a durable and tamper-evident trail of the security events.
+//!
+//! Every entry is a JSON line carrying the hash of the previous entry, so that editing or
+//! removing an entry breaks the chain. The sequence number and hash of the last entry are
+//! also kept in a separate head file, which allows detecting a truncation of the log.
+//!
+//! The entries are written by a dedicated thread, so that the request handlers never wait for
+//! the disk.
+
+use crate::config;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread;
+use tokio::task;
+use time::OffsetDateTime;
+
+pub use lab3_protocol::{Event, EventInfo, Outcome, Page, Query};
+
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+/// Hash used as previous hash by the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+static AUDIT: OnceLock<Mutex<AuditLog>> = OnceLock::new();
+
+/// Queue of the thread writing the audit log
+static WRITER: OnceLock<Sender<Job>> = OnceLock::new();
+
+/// Work of the thread writing the audit log, done in order
+enum Job {
+    Append { actor: Option<String>, event: Event, target: Option<String>, outcome: Outcome },
+    /// Answered once every previous entry reached the disk
+    Flush(Sender<Result<(), String>>),
+}
+
+/// Content of an entry covered by its hash
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Record {
+    seq: u64,
+    timestamp: i64,
+    actor: Option<String>,
+    event: Event,
+    target: Option<String>,
+    outcome: Outcome,
+    prev_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Entry {
+    #[serde(flatten)]
+    record: Record,
+    hash: String,
+}
+
+/// Sequence number and hash of the last entry
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Head {
+    seq: u64,
+    hash: String,
+}
+
+struct AuditLog {
+    path: String,
+    file: File,
+    head_path: String,
+    head: Option<Head>,
+}
+
+impl Record {
+    fn hash(&self) -> Result<String, Box<dyn Error>> {
+        Ok(format!("{:x}", Sha256::digest(serde_json::to_vec(self)?)))
+    }
+}
+
+/// Check a record against every filter set in a query
+fn matches(q: &Query, r: &Record) -> bool {
+    let same = |filter: &Option<String>, value: &Option<String>| {
+        filter.as_ref().is_none_or(|f| value.as_ref().is_some_and(|v| v.eq_ignore_ascii_case(f)))
+    };
+
+    same(&q.actor, &r.actor)
+        && same(&q.target, &r.target)
+        && q.event.is_none_or(|e| e == r.event)
+        && q.outcome.is_none_or(|o| o == r.outcome)
+        && q.from.is_none_or(|from| r.timestamp >= from)
+        && q.to.is_none_or(|to| r.timestamp < to)
+}
+
+impl From<Record> for EventInfo {
+    fn from(r: Record) -> Self {
+        EventInfo {
+            seq: r.seq,
+            timestamp: r.timestamp,
+            actor: r.actor,
+            event: r.event,
+            target: r.target,
+            outcome: r.outcome,
+        }
+    }
+}
+
+impl AuditLog {
+    /// Open the log for appending and resume the chain from its last entry
+    ///
+    /// # Error
+    /// If the chain is broken: the new entries would look legitimate if they were chained to a
+    /// log which was edited or truncated.
+    fn open(path: &str, head_path: &str) -> Result<AuditLog, Box<dyn Error>> {
+        let head = verify(path, head_path).map_err(|e| format!(
+            "audit log \"{}\" is broken: {}. Keep it as evidence, then move it and \"{}\" aside to start a new chain",
+            path, e, head_path))?;
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { path: path.to_string(), file, head_path: head_path.to_string(), head })
+    }
+
+    fn append(&mut self, actor: Option<&str>, event: Event, target: Option<&str>, outcome: Outcome) -> Result<(), Box<dyn Error>> {
+        let (seq, prev_hash) = match &self.head {
+            Some(head) => (head.seq + 1, head.hash.clone()),
+            None => (0, GENESIS_HASH.to_string()),
+        };
+
+        let record = Record {
+            seq,
+            timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+            actor: actor.map(str::to_string),
+            event,
+            target: target.map(str::to_string),
+            outcome,
+            prev_hash,
+        };
+        let entry = Entry { hash: record.hash()?, record };
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+
+        let head = Head { seq, hash: entry.hash };
+        write_head(&self.head_path, &head)?;
+        self.head = Some(head);
+        Ok(())
+    }
+}
+
+/// Open the audit log at startup so that a broken chain stops the server before accepting
+/// clients
+pub fn init() -> Result<(), Box<dyn Error>> {
+    let path = &config::get().audit.path;
+    let audit = AuditLog::open(path, &head_path(path))?;
+    AUDIT.set(Mutex::new(audit)).map_err(|_| "audit log already opened")?;
+
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new().name("audit".to_string()).spawn(move || write_jobs(receiver))?;
+    WRITER.set(sender).map_err(|_| "audit log already opened")?;
+    Ok(())
+}
+
+// Loop of the thread writing the audit log, until the server stops
+fn write_jobs(jobs: Receiver<Job>) {
+    for job in jobs {
+        match job {
+            Job::Append { actor, event, target, outcome } => {
+                let res = audit().and_then(|mut audit| audit.append(actor.as_deref(), event, target.as_deref(), outcome));
+                if let Err(e) = res {
+                    error!("failed to write audit entry: {}", e);
+                }
+            }
+            Job::Flush(done) => {
+                let res = audit().and_then(|audit| Ok(audit.file.sync_all()?));
+                let _ = done.send(res.map_err(|e| e.to_string()));
+            }
+        }
+    }
+}
+
+fn audit() -> Result<MutexGuard<'static, AuditLog>, Box<dyn Error>> {
+    Ok(AUDIT.get().ok_or("audit log not opened")?.lock().map_err(|_| "audit log lock poisoned")?)
+}
+
+/// Queue an event for the audit log. A failure to write is logged but does not interrupt the
+/// action that triggered it.
+pub fn record(actor: Option<&str>, event: Event, target: Option<&str>, outcome: Outcome) {
+    let job = Job::Append { actor: actor.map(str::to_string), event, target: target.map(str::to_string), outcome };
+    let sent = WRITER.get().ok_or("audit log not opened").and_then(|w| w.send(job).map_err(|_| "audit writer stopped"));
+
+    if let Err(e) = sent {
+        error!("failed to write audit entry: {}", e);
+    }
+}
+
+/// Wait until every queued entry reached the disk, used before the server stops
+pub fn flush() -> Result<(), Box<dyn Error>> {
+    let (done, answer) = mpsc::channel();
+    WRITER.get().ok_or("audit log not opened")?.send(Job::Flush(done)).map_err(|_| "audit writer stopped")?;
+    Ok(answer.recv().map_err(|_| "audit writer stopped")??)
+}
+
+/// Search the audit log on the blocking pool, the events are returned in chronological order
+///
+/// # Error
+/// If the page size is not between 1 and `MAX_PAGE_SIZE` or the log cannot be read.
+pub async fn query(q: Query) -> Result<Page, Box<dyn Error>> {
+    Ok(task::spawn_blocking(move || search(&q).map_err(|e| e.to_string())).await??)
+}
+
+fn search(q: &Query) -> Result<Page, Box<dyn Error>> {
+    if q.page_size == 0 || q.page_size > MAX_PAGE_SIZE {
+        Err(format!("Page size must be between 1 and {}", MAX_PAGE_SIZE))?
+    }
+
+    // Hold the lock so that no entry is being appended while reading
+    let audit = audit()?;
+    let matching: Vec<Record> = read_lines(&audit.path)?
+        .iter()
+        .filter_map(|l| serde_json::from_str::<Entry>(l).ok())
+        .map(|e| e.record)
+        .filter(|r| matches(q, r))
+        .collect();
+
+    Ok(Page {
+        total: matching.len() as u64,
+        events: matching
+            .into_iter()
+            .skip(q.page as usize * q.page_size as usize)
+            .take(q.page_size as usize)
+            .map(EventInfo::from)
+            .collect(),
+    })
+}
+
+/// Verify the audit log of the server config, returns the number of entries
+pub fn verify_default() -> Result<u64, Box<dyn Error>> {
+    let path = &config::get().audit.path;
+    Ok(verify(path, &head_path(path))?.map_or(0, |h| h.seq + 1))
+}
+
+/// The head of an audit log is kept next to it
+fn head_path(path: &str) -> String {
+    format!("{}.head", path)
+}
+
+/// Check the whole chain of an audit log against its head file, returns the last entry.
+///
+/// The head is written after the entry, so a crash between both leaves one valid entry after
+/// the head: the head is then moved to this entry.
+///
+/// # Error
+/// If an entry was edited, removed or reordered, or if the log was truncated.
+fn verify(path: &str, head_path: &str) -> Result<Option<Head>, Box<dyn Error>> {
+    let mut last: Option<Head> = None;
+    let mut previous: Option<Head> = None;
+
+    for (i, line) in read_lines(path)?.into_iter().enumerate() {
+        let entry: Entry = serde_json::from_str(&line)
+            .map_err(|e| format!("entry {} is malformed: {}", i, e))?;
+
+        let expected_prev = last.as_ref().map_or(GENESIS_HASH, |h| h.hash.as_str());
+        if entry.record.seq != i as u64 {
+            Err(format!("entry {} has sequence number {}", i, entry.record.seq))?
+        }
+        if entry.record.prev_hash != expected_prev {
+            Err(format!("entry {} does not follow the previous entry", i))?
+        }
+        if entry.record.hash()? != entry.hash {
+            Err(format!("entry {} was modified", i))?
+        }
+
+        previous = last.replace(Head { seq: entry.record.seq, hash: entry.hash });
+    }
+
+    let head = match fs::read(head_path) {
+        Ok(raw) => Some(serde_json::from_slice::<Head>(&raw)?),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => Err(e)?,
+    };
+
+    if head != last && head == previous {
+        warn!("audit log \"{}\" has one entry after its head, the server stopped while writing it", path);
+        if let Some(last) = &last {
+            write_head(head_path, last)?;
+        }
+    } else if head != last {
+        let count = |h: &Option<Head>| h.as_ref().map_or(0, |h| h.seq + 1);
+        Err(format!("log was truncated or replaced: head expects {} entries, found {}", count(&head), count(&last)))?
+    }
+
+    Ok(last)
+}
+
+fn read_lines(path: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    match File::open(path) {
+        Ok(file) => Ok(BufReader::new(file).lines().collect::<Result<_, _>>()?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Replace the head file atomically, it reaches the disk before returning
+fn write_head(path: &str, head: &Head) -> Result<(), Box<dyn Error>> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(head)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    // The rename is only durable once the directory is written
+    let dir = Path::new(path).parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Paths of a fresh audit log in the temporary directory
+    fn paths(name: &str) -> (String, String) {
+        let dir: PathBuf = std::env::temp_dir().join(format!("lab3_audit_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("audit.log").to_string_lossy().to_string();
+        let head = dir.join("audit.log.head").to_string_lossy().to_string();
+        (log, head)
+    }
+
+    fn write_entries(log: &str, head: &str, count: usize) {
+        let mut audit = AuditLog::open(log, head).unwrap();
+        for _ in 0..count {
+            audit.append(Some("default_hr"), Event::ChangePhone, Some("default_user"), Outcome::Success).unwrap();
+        }
+    }
+
+    #[test]
+    fn verify_intact_log() {
+        let (log, head) = paths("intact");
+        write_entries(&log, &head, 3);
+        // Reopening continues the same chain
+        write_entries(&log, &head, 2);
+
+        assert_eq!(verify(&log, &head).unwrap().unwrap().seq, 4);
+    }
+
+    #[test]
+    fn detect_edited_entry() {
+        let (log, head) = paths("edited");
+        write_entries(&log, &head, 3);
+
+        let content = fs::read_to_string(&log).unwrap().replacen("default_user", "default_admin", 1);
+        fs::write(&log, content).unwrap();
+
+        assert!(verify(&log, &head).is_err());
+    }
+
+    #[test]
+    fn detect_removed_entry() {
+        let (log, head) = paths("removed");
+        write_entries(&log, &head, 3);
+
+        let lines: Vec<String> = read_lines(&log).unwrap();
+        fs::write(&log, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+
+        assert!(verify(&log, &head).is_err());
+    }
+
+    #[test]
+    fn detect_truncation() {
+        let (log, head) = paths("truncated");
+        write_entries(&log, &head, 3);
+
+        let lines: Vec<String> = read_lines(&log).unwrap();
+        fs::write(&log, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+
+        assert!(verify(&log, &head).is_err());
+    }
+
+    #[test]
+    fn entry_after_head_is_recovered() {
+        let (log, head) = paths("crash");
+        write_entries(&log, &head, 3);
+        let before = fs::read(&head).unwrap();
+        write_entries(&log, &head, 1);
+
+        // The server stopped after writing the entry, before its head
+        fs::write(&head, &before).unwrap();
+        assert_eq!(verify(&log, &head).unwrap().unwrap().seq, 3);
+        assert_ne!(fs::read(&head).unwrap(), before);
+        write_entries(&log, &head, 1);
+        assert_eq!(verify(&log, &head).unwrap().unwrap().seq, 4);
+
+        // Without any head, only the first entry can have been written before the stop
+        let (log, head) = paths("crash_first");
+        write_entries(&log, &head, 1);
+        fs::remove_file(&head).unwrap();
+        assert_eq!(verify(&log, &head).unwrap().unwrap().seq, 0);
+        assert!(fs::metadata(&head).is_ok());
+
+        // Two entries after the head are not the result of a stop
+        let (log, head) = paths("crash_twice");
+        write_entries(&log, &head, 1);
+        let before = fs::read(&head).unwrap();
+        write_entries(&log, &head, 2);
+        fs::write(&head, &before).unwrap();
+        assert!(verify(&log, &head).is_err());
+    }
+
+    #[test]
+    fn broken_log_is_not_continued() {
+        let (log, head) = paths("broken");
+        write_entries(&log, &head, 3);
+
+        let lines: Vec<String> = read_lines(&log).unwrap();
+        fs::write(&log, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+
+        let e = AuditLog::open(&log, &head).err().unwrap();
+        assert!(e.to_string().contains("is broken"));
+        assert_eq!(read_lines(&log).unwrap().len(), 2);
+
+        // Moving the broken log aside starts a new chain
+        fs::rename(&log, format!("{}.broken", log)).unwrap();
+        fs::remove_file(&head).unwrap();
+        write_entries(&log, &head, 1);
+        assert_eq!(verify(&log, &head).unwrap().unwrap().seq, 0);
+    }
+
+    #[test]
+    fn query_filters() {
+        let record = Record {
+            seq: 0,
+            timestamp: 1000,
+            actor: Some("default_hr".to_string()),
+            event: Event::ChangePhone,
+            target: Some("default_user".to_string()),
+            outcome: Outcome::Success,
+            prev_hash: GENESIS_HASH.to_string(),
+        };
+        let all = Query { actor: None, target: None, event: None, outcome: None, from: None, to: None, page: 0, page_size: 10 };
+
+        assert!(matches(&all, &record));
+        assert!(matches(&Query { actor: Some("DEFAULT_HR".to_string()), ..all.clone() }, &record));
+        assert!(!matches(&Query { actor: Some("default_user".to_string()), ..all.clone() }, &record));
+        assert!(!matches(&Query { event: Some(Event::AddUser), ..all.clone() }, &record));
+        assert!(!matches(&Query { outcome: Some(Outcome::Denied), ..all.clone() }, &record));
+        assert!(matches(&Query { from: Some(1000), to: Some(1001), ..all.clone() }, &record));
+        assert!(!matches(&Query { to: Some(1000), ..all.clone() }, &record));
+    }
+}
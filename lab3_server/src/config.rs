@@ -0,0 +1,473 @@
+//! This file is used to read the configuration of the server.
+//!
+//! The settings are read from a TOML file, then overridden by the environment variables and
+//! finally by the command-line arguments. Every setting has a default value, so the file is
+//! optional. The configuration is checked as a whole before the server starts.
+
+use crate::tls::{Backend, ClientAuth};
+use argon2::Params;
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+use simplelog::LevelFilter;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// File read when no configuration file is given, it is optional
+const DEFAULT_CONFIG_PATH: &str = "server.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+#[derive(Parser, Debug)]
+#[command(about = "Server of the HR online user directory")]
+pub struct Cli {
+    /// TOML configuration file, server.toml is read if it exists
+    #[arg(long, env = "SERVER_CONFIG")]
+    config: Option<String>,
+
+    #[command(flatten)]
+    overrides: Overrides,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Check the chain of the audit log and exit
+    VerifyAudit,
+}
+
+/// Settings which can be given on the command line or in the environment
+#[derive(Args, Debug)]
+struct Overrides {
+    /// Address and port to listen on
+    #[arg(long, env = "SERVER_ADDRESS")]
+    address: Option<String>,
+    /// Level of the logs: off, error, warn, info, debug or trace
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+    /// PEM certificate of the server
+    #[arg(long, env = "CERT_PATH")]
+    cert: Option<String>,
+    /// PKCS8 private key of the server, it can be encrypted
+    #[arg(long, env = "KEY_PATH")]
+    key: Option<String>,
+    /// TLS backend: rustls or native-tls
+    #[arg(long, env = "TLS_BACKEND")]
+    tls_backend: Option<String>,
+    /// PEM file of the CA signing the client certificates
+    #[arg(long, env = "CLIENT_CA")]
+    client_ca: Option<String>,
+    /// Client certificate: optional or required
+    #[arg(long, env = "CLIENT_AUTH")]
+    client_auth: Option<String>,
+    /// Database file
+    #[arg(long, env = "DB_PATH")]
+    db: Option<String>,
+    /// Directory of the database snapshots
+    #[arg(long, env = "BACKUP_DIR")]
+    backups: Option<String>,
+    /// Casbin model of the access control
+    #[arg(long, env = "POLICY_MODEL")]
+    policy_model: Option<String>,
+    /// Casbin policy of the access control
+    #[arg(long, env = "POLICY_FILE")]
+    policy: Option<String>,
+    /// Audit log, its head is kept next to it
+    #[arg(long, env = "AUDIT_LOG")]
+    audit_log: Option<String>,
+    /// Memory cost of Argon2id, in KiB
+    #[arg(long, env = "ARGON2_MEMORY")]
+    argon2_memory: Option<u32>,
+    /// Number of passes of Argon2id
+    #[arg(long, env = "ARGON2_PASSES")]
+    argon2_passes: Option<u32>,
+    /// Degree of parallelism of Argon2id
+    #[arg(long, env = "ARGON2_LANES")]
+    argon2_lanes: Option<u32>,
+    /// Length of the Argon2id hashes, in bytes
+    #[arg(long, env = "ARGON2_OUTPUT")]
+    argon2_output: Option<usize>,
+    /// Clients served at the same time
+    #[arg(long, env = "MAX_CONNECTIONS")]
+    max_connections: Option<u64>,
+    /// Seconds allowed for the TLS handshake and the hello
+    #[arg(long, env = "READ_TIMEOUT")]
+    read_timeout: Option<u64>,
+    /// Seconds allowed between two requests
+    #[arg(long, env = "IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+    /// Maximum duration of a session since the login, in seconds
+    #[arg(long, env = "SESSION_LIFETIME")]
+    session_lifetime: Option<u64>,
+    /// Seconds given to the requests in progress when the server stops
+    #[arg(long, env = "DRAIN_TIMEOUT")]
+    drain_timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub database: DatabaseConfig,
+    pub access: AccessConfig,
+    pub audit: AuditConfig,
+    pub argon2: Argon2Config,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub log_level: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    pub backend: String,
+    /// Client certificates are not asked if no CA is set
+    pub client_ca: Option<String>,
+    pub client_auth: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: String,
+    pub backups: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    pub model: String,
+    pub policy: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub path: String,
+}
+
+/// Parameters of Argon2id, the memory is in KiB and the output length in bytes
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2Config {
+    pub memory: u32,
+    pub passes: u32,
+    pub lanes: u32,
+    pub output: usize,
+}
+
+/// Limits applied to the connections, the durations are in seconds
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_connections: u64,
+    pub read_timeout: u64,
+    pub idle_timeout: u64,
+    pub session_lifetime: u64,
+    pub drain_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { address: "localhost:4444".to_string(), log_level: "info".to_string() }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: "./tls/public/ec_cert.pem".to_string(),
+            key: "./tls/private/ec_private_pkcs8".to_string(),
+            backend: if cfg!(feature = "rustls") { "rustls" } else { "native-tls" }.to_string(),
+            client_ca: None,
+            client_auth: "optional".to_string(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { path: "db.ron".to_string(), backups: "backups".to_string() }
+    }
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig {
+            model: "access_policy/model.conf".to_string(),
+            policy: "access_policy/policy.csv".to_string(),
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig { path: "audit.log".to_string() }
+    }
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Argon2Config { memory: 65536, passes: 3, lanes: 4, output: 64 }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_connections: 100,
+            read_timeout: 30,
+            idle_timeout: 5 * 60,
+            session_lifetime: 8 * 60 * 60,
+            drain_timeout: 10,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration file named on the command line, in the environment or the default
+    /// one, and apply the overrides
+    pub fn load(cli: &Cli) -> Result<Config, String> {
+        let mut config = match &cli.config {
+            Some(path) => Config::read(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::read(DEFAULT_CONFIG_PATH)?,
+            None => Config::default(),
+        };
+        config.apply(&cli.overrides);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: &str) -> Result<Config, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read the configuration file \"{}\": {}", path, e))?;
+        toml::from_str(&content).map_err(|e| format!("invalid configuration file \"{}\": {}", path, e))
+    }
+
+    fn apply(&mut self, o: &Overrides) {
+        fn set<T: Clone>(setting: &mut T, value: &Option<T>) {
+            if let Some(value) = value {
+                *setting = value.clone();
+            }
+        }
+        set(&mut self.server.address, &o.address);
+        set(&mut self.server.log_level, &o.log_level);
+        set(&mut self.tls.cert, &o.cert);
+        set(&mut self.tls.key, &o.key);
+        set(&mut self.tls.backend, &o.tls_backend);
+        if o.client_ca.is_some() {
+            self.tls.client_ca = o.client_ca.clone();
+        }
+        set(&mut self.tls.client_auth, &o.client_auth);
+        set(&mut self.database.path, &o.db);
+        set(&mut self.database.backups, &o.backups);
+        set(&mut self.access.model, &o.policy_model);
+        set(&mut self.access.policy, &o.policy);
+        set(&mut self.audit.path, &o.audit_log);
+        set(&mut self.argon2.memory, &o.argon2_memory);
+        set(&mut self.argon2.passes, &o.argon2_passes);
+        set(&mut self.argon2.lanes, &o.argon2_lanes);
+        set(&mut self.argon2.output, &o.argon2_output);
+        set(&mut self.limits.max_connections, &o.max_connections);
+        set(&mut self.limits.read_timeout, &o.read_timeout);
+        set(&mut self.limits.idle_timeout, &o.idle_timeout);
+        set(&mut self.limits.session_lifetime, &o.session_lifetime);
+        set(&mut self.limits.drain_timeout, &o.drain_timeout);
+    }
+
+    /// Check every setting, the error names the first invalid one
+    fn validate(&self) -> Result<(), String> {
+        match self.server.address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => Err(format!("server.address must have the format host:port, found \"{}\"", self.server.address))?,
+        }
+        self.log_level()?;
+
+        existing_file("tls.cert", &self.tls.cert)?;
+        existing_file("tls.key", &self.tls.key)?;
+        if let Some(ca) = &self.tls.client_ca {
+            existing_file("tls.client_ca", ca)?;
+        }
+        let backend = self.tls_backend()?;
+        if self.client_auth()?.is_some() && backend == Backend::NativeTls {
+            Err("tls.client_ca: client certificates require the rustls backend")?
+        }
+        existing_file("access.model", &self.access.model)?;
+        existing_file("access.policy", &self.access.policy)?;
+
+        new_file("database.path", &self.database.path)?;
+        if self.database.backups.is_empty() || Path::new(&self.database.backups).is_file() {
+            Err(format!("database.backups must be a directory, found \"{}\"", self.database.backups))?
+        }
+
+        new_file("audit.path", &self.audit.path)?;
+        self.argon2_params()?;
+
+        let limits = [
+            ("limits.max_connections", self.limits.max_connections),
+            ("limits.read_timeout", self.limits.read_timeout),
+            ("limits.idle_timeout", self.limits.idle_timeout),
+            ("limits.session_lifetime", self.limits.session_lifetime),
+            ("limits.drain_timeout", self.limits.drain_timeout),
+        ];
+        for (name, value) in limits {
+            if value == 0 {
+                Err(format!("{} must be a positive number", name))?
+            }
+        }
+        if self.limits.max_connections > u32::MAX as u64 {
+            Err(format!("limits.max_connections must be at most {}", u32::MAX))?
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter, String> {
+        self.server.log_level.parse().map_err(|_| format!(
+            "server.log_level must be off, error, warn, info, debug or trace, found \"{}\"",
+            self.server.log_level))
+    }
+
+    pub fn tls_backend(&self) -> Result<Backend, String> {
+        match self.tls.backend.as_str() {
+            "native-tls" => Ok(Backend::NativeTls),
+            "rustls" if cfg!(feature = "rustls") => Ok(Backend::Rustls),
+            "rustls" => Err("tls.backend is rustls but the server was built without the rustls feature".to_string()),
+            other => Err(format!("tls.backend must be native-tls or rustls, found \"{}\"", other)),
+        }
+    }
+
+    /// Client certificates accepted by the server, `None` if no CA is set
+    pub fn client_auth(&self) -> Result<Option<ClientAuth>, String> {
+        let required = match self.tls.client_auth.as_str() {
+            "optional" => false,
+            "required" => true,
+            other => Err(format!("tls.client_auth must be optional or required, found \"{}\"", other))?,
+        };
+        match &self.tls.client_ca {
+            Some(ca_file) => Ok(Some(ClientAuth { ca_file: ca_file.clone(), required })),
+            None if required => Err("tls.client_auth is required but no tls.client_ca is set".to_string()),
+            None => Ok(None),
+        }
+    }
+
+    pub fn argon2_params(&self) -> Result<Params, String> {
+        let a = &self.argon2;
+        Params::new(a.memory, a.passes, a.lanes, Some(a.output)).map_err(|e| format!(
+            "argon2 parameters are invalid ({}): memory must be at least 8 KiB per lane, passes at \
+             least 1, lanes between 1 and 16777215 and output at least 4 bytes", e))
+    }
+}
+
+fn existing_file(name: &str, path: &str) -> Result<(), String> {
+    if Path::new(path).is_file() {
+        Ok(())
+    } else {
+        Err(format!("{}: file \"{}\" does not exist", name, path))
+    }
+}
+
+// Check a file created by the server if needed, its directory must exist
+fn new_file(name: &str, path: &str) -> Result<(), String> {
+    if path.is_empty() || Path::new(path).is_dir() {
+        Err(format!("{} must be a file, found \"{}\"", name, path))?
+    }
+    match Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(dir) if !dir.is_dir() => Err(format!("{}: directory \"{}\" does not exist", name, dir.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Set the configuration of the server once it is loaded
+pub fn init(config: Config) {
+    CONFIG.set(config).expect("configuration already set");
+}
+
+/// Configuration of the server, the default one if it was not set
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    /// Parse the arguments without reading the environment of the process
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        let matches = Cli::command().mut_args(|arg| arg.env(None)).try_get_matches_from(args)?;
+        Cli::from_arg_matches(&matches)
+    }
+
+    #[test]
+    fn partial_file_keeps_defaults() {
+        let config: Config = toml::from_str("[argon2]\npasses = 4\n").unwrap();
+        assert_eq!(config.argon2.passes, 4);
+        assert_eq!(config.argon2.memory, 65536);
+        assert_eq!(config.database.path, "db.ron");
+    }
+
+    #[test]
+    fn unknown_setting_is_refused() {
+        let e = toml::from_str::<Config>("[database]\nfile = \"db.ron\"\n").unwrap_err();
+        assert!(e.to_string().contains("unknown field `file`"));
+    }
+
+    #[test]
+    fn invalid_settings_are_named() {
+        let mut config = Config::default();
+        config.argon2.lanes = 0;
+        assert!(config.argon2_params().unwrap_err().starts_with("argon2 parameters are invalid"));
+
+        config.server.log_level = "verbose".to_string();
+        assert!(config.log_level().unwrap_err().starts_with("server.log_level"));
+
+        config.server.address = "localhost".to_string();
+        assert!(config.validate().unwrap_err().starts_with("server.address"));
+    }
+
+    #[test]
+    fn argon2_overrides_are_validated() {
+        let mut config = Config::default();
+        let cli = parse(&["lab3_server", "--argon2-memory", "19456", "--argon2-passes", "2"]).unwrap();
+        config.apply(&cli.overrides);
+        let params = config.argon2_params().unwrap();
+        assert_eq!((params.m_cost(), params.t_cost(), params.p_cost()), (19456, 2, 4));
+
+        let cli = parse(&["lab3_server", "--argon2-lanes", "0"]).unwrap();
+        config.apply(&cli.overrides);
+        assert!(config.argon2_params().unwrap_err().starts_with("argon2 parameters are invalid"));
+        assert!(parse(&["lab3_server", "--argon2-memory", "-1"]).is_err());
+    }
+
+    #[test]
+    fn tls_and_audit_settings_are_checked() {
+        let config: Config = toml::from_str("[tls]\nbackend = \"native-tls\"\n").unwrap();
+        assert_eq!(config.tls_backend(), Ok(Backend::NativeTls));
+        assert_eq!(config.client_auth(), Ok(None));
+
+        let mut config = Config::default();
+        config.tls.backend = "openssl".to_string();
+        assert!(config.tls_backend().unwrap_err().starts_with("tls.backend"));
+
+        config.tls.client_auth = "required".to_string();
+        assert!(config.client_auth().unwrap_err().contains("no tls.client_ca"));
+        config.tls.client_ca = Some("ca.pem".to_string());
+        assert!(config.client_auth().unwrap().unwrap().required);
+        config.tls.client_auth = "always".to_string();
+        assert!(config.client_auth().unwrap_err().starts_with("tls.client_auth"));
+
+        assert!(new_file("audit.path", "audit.log").is_ok());
+        assert!(new_file("audit.path", "missing/audit.log").unwrap_err().starts_with("audit.path: directory"));
+        assert!(new_file("audit.path", "").is_err());
+    }
+}
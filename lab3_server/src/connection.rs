@@ -1,29 +1,58 @@
-use native_tls::TlsStream;
+//! This file is used to exchange messages with a client, using the framing of the protocol
+
+use lab3_protocol::frame::{deserialize, read_frame_async, serialize, write_frame_async};
+use log::error;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::error::Error;
-use std::net::TcpStream;
+use std::io;
+use std::io::ErrorKind;
+use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::Zeroizing;
+
+/// Encrypted stream of a client, whatever the TLS backend
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
 
 pub struct Connection {
-    stream: TlsStream<TcpStream>,
+    stream: Box<dyn Stream>,
 }
 
 impl Connection {
-    pub fn new(stream: TlsStream<TcpStream>) -> Connection {
+    pub fn new(stream: Box<dyn Stream>) -> Connection {
         Connection { stream }
     }
 
-    pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>>
+    pub async fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>>
     where
         T: Serialize,
     {
-        Ok(bincode::serialize_into(&mut self.stream, &o)?)
+        // The payload may carry a session token
+        let payload = Zeroizing::new(serialize(o)?);
+        write_frame_async(&mut self.stream, &payload).await.map_err(|e| e as Box<dyn Error>)
     }
 
-    pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>>
+    pub async fn receive<T>(&mut self) -> Result<T, Box<dyn Error>>
     where
         T: DeserializeOwned,
     {
-        Ok(bincode::deserialize_from(&mut self.stream)?)
+        // The payload may carry a password, it is erased once decoded
+        let payload = read_frame_async(&mut self.stream).await.map(Zeroizing::new).map_err(|e| {
+            // A client closing the connection is not worth an error
+            let closed = e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof);
+            if !closed {
+                error!("Invalid frame received: {}", e);
+            }
+            e as Box<dyn Error>
+        })?;
+
+        match deserialize(&payload) {
+            Ok(o) => Ok(o),
+            Err(e) => {
+                error!("Malformed message received: {}", e);
+                Err(e)?
+            }
+        }
     }
 }
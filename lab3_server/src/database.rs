@@ -1,53 +1,196 @@
-/// This file is used to store and retrieve user accounts from the database
+//! This file is used to store and retrieve user accounts from the database
+//!
+//! The accounts are read from memory. The operations writing a file run on the blocking pool,
+//! so that the workers serving the clients never wait for the disk.
 
+use crate::argon2::{hash_password_now, validate_hash};
+use crate::config;
+use crate::migration::{migrate, CURRENT_VERSION};
 use crate::user::{UserAccount, UserInfo, UserRole};
-use lazy_static::lazy_static;
-use rustbreak::{deser::Ron, FileDatabase};
+use crate::validator::{validate_password, validate_phone, validate_snapshot_name, validate_username};
+use rustbreak::{deser::{DeSerializer, Ron}, FileDatabase};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::error::Error;
-use log::info;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{stderr, IsTerminal, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::io;
+use std::io::ErrorKind;
+use log::{info, warn};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::OsRng;
+use time::OffsetDateTime;
+use tokio::task;
+use zeroize::Zeroizing;
 
-lazy_static! {
-    static ref DB: FileDatabase<Database, Ron> =
-        FileDatabase::load_from_path_or_default("db.ron").unwrap();
+static DB: OnceLock<FileDatabase<Database, Ron>> = OnceLock::new();
+
+fn db() -> Result<&'static FileDatabase<Database, Ron>, Box<dyn Error>> {
+    Ok(DB.get().ok_or("database not loaded")?)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Database {
+    version: u32,
     data: HashMap<String, UserAccount>,
 }
 
 impl Database {
-    pub fn insert(user: &UserAccount) -> Result<(), Box<dyn Error>> {
-        DB.write(|db| db.data.insert(user.username().to_string(), user.clone()))?;
-        DB.save()?;
-        info!("database updated");
+    pub fn new(data: HashMap<String, UserAccount>) -> Self {
+        Database {
+            version: CURRENT_VERSION,
+            data,
+        }
+    }
+
+    /// Load the database at startup, so that migrations and loading errors happen before any
+    /// client is accepted
+    pub fn init() -> Result<(), Box<dyn Error>> {
+        let db = Database::open(&config::get().database.path)?;
+        DB.set(db).map_err(|_| "database already loaded")?;
+        Ok(())
+    }
+
+    /// Load the database file, upgrading it to the current version if needed.
+    /// The original file is kept next to it before being overwritten by a migration.
+    fn open(path: &str) -> Result<FileDatabase<Database, Ron>, Box<dyn Error>> {
+        let db = match fs::read(path) {
+            Ok(raw) => {
+                let (db, version) = migrate(&raw)?;
+                if version != CURRENT_VERSION {
+                    let backup = format!("{}.v{}.bak", path, version);
+                    fs::write(&backup, &raw)?;
+                    warn!("database migrated from version {} to {}, original kept in \"{}\"", version, CURRENT_VERSION, backup);
+                }
+                db
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Database::initial(path)?,
+            Err(e) => Err(e)?,
+        };
+
+        let file_db = FileDatabase::create_at_path(path, db)?;
+        file_db.save()?;
+        Ok(file_db)
+    }
+
+    pub fn user(&self, username: &str) -> Option<&UserAccount> {
+        self.data.get(username)
+    }
+
+    /// Add a new account, returns `false` without changing anything if the username is taken.
+    /// The check and the insertion are done under the same lock.
+    pub async fn insert_new(user: UserAccount) -> Result<bool, Box<dyn Error>> {
+        blocking(move || {
+            let added = db()?.write(|db| match db.data.entry(user.username().to_string()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(user);
+                    true
+                }
+                Entry::Occupied(_) => false,
+            })?;
+
+            if added {
+                db()?.save()?;
+                info!("database updated");
+            }
+            Ok(added)
+        }).await
+    }
+
+    /// Change the account of a user under the write lock, so that a concurrent change of the same
+    /// account cannot be lost. Returns `None` if the user does not exist. The database is only
+    /// saved if the change succeeds.
+    pub async fn update<T, E, F>(username: &str, change: F) -> Result<Option<Result<T, E>>, Box<dyn Error>>
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+        F: FnOnce(&mut UserAccount) -> Result<T, E> + Send + 'static,
+    {
+        let username = username.to_string();
+        blocking(move || {
+            let res = db()?.write(|db| db.data.get_mut(&username).map(change))?;
+
+            if let Some(Ok(_)) = res {
+                db()?.save()?;
+                info!("database updated");
+            }
+            Ok(res)
+        }).await
+    }
+
+    /// Write the database to its file, used before the server stops
+    pub fn flush() -> Result<(), Box<dyn Error>> {
+        db()?.save()?;
         Ok(())
     }
 
     pub fn get(username: &str) -> Result<Option<UserAccount>, Box<dyn Error>> {
-        Ok(match DB.borrow_data()?.data.get(username) {
-            Some(user) => Some(user.clone()),
-            None => None,
-        })
+        Ok(db()?.borrow_data()?.user(username).cloned())
     }
 
     pub fn get_all_user_info() -> Result<Vec<UserInfo>, Box<dyn Error>> {
-        Ok(DB.borrow_data()?.data
+        Ok(db()?.borrow_data()?.data
             .values()
-            .cloned()
             .map(|u| {
                 UserInfo::new(String::from(u.username()), String::from(u.phone_number()))})
             .collect())
     }
-}
 
-impl Default for Database {
-    fn default() -> Self {
-        let mut db = Database {
-            data: HashMap::new(),
-        };
+    /// Write a snapshot of the whole database in the backup directory and return its name.
+    /// The data is serialized while holding the read lock, so the snapshot is consistent
+    /// even if other clients keep updating the database.
+    pub async fn backup() -> Result<String, Box<dyn Error>> {
+        blocking(|| {
+            let data = db()?.read(|db| Ron.serialize(db))??;
+            write_snapshot(Path::new(&config::get().database.backups), &data)
+        }).await
+    }
+
+    /// Read a snapshot from the backup directory and validate every record
+    ///
+    /// # Error
+    /// If the name is invalid, the file cannot be read or a record is invalid.
+    pub async fn read_snapshot(name: &str) -> Result<Database, Box<dyn Error>> {
+        let name = name.to_string();
+        blocking(move || read_snapshot(Path::new(&config::get().database.backups), &name)).await
+    }
+
+    /// Replace the whole database by a snapshot previously read with `read_snapshot`
+    pub async fn restore(snapshot: Database) -> Result<(), Box<dyn Error>> {
+        blocking(|| {
+            db()?.write(|db| *db = snapshot)?;
+            db()?.save()?;
+            info!("database restored");
+            Ok(())
+        }).await
+    }
+
+    /// Check every record of the database against the server policies
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        for (key, user) in &self.data {
+            if key != user.username() || !validate_username(user.username()) {
+                Err(format!("Invalid username for record \"{}\"", key))?
+            }
+            if !validate_phone(user.phone_number()) {
+                Err(format!("Invalid phone number for record \"{}\"", key))?
+            }
+            if !validate_hash(user.password()) {
+                Err(format!("Invalid password hash for record \"{}\"", key))?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Accounts created with a new database file. The privileged accounts get a random
+    /// password, shown once, instead of a password known by everyone.
+    fn initial(path: &str) -> Result<Database, Box<dyn Error>> {
+        let mut db = Database::new(HashMap::new());
 
         // Password is Test1234.
         // => please use this account only for setup and remove it in prod
@@ -67,9 +210,160 @@ impl Default for Database {
             UserRole::HR,
         );
 
+        let u3 = UserAccount::new(
+            "default_admin".to_string(),
+            initial_password(path, "default_admin")?,
+            "0781234567".to_string(),
+            UserRole::Admin,
+        );
+
+        let u4 = UserAccount::new(
+            "default_auditor".to_string(),
+            initial_password(path, "default_auditor")?,
+            "0787654321".to_string(),
+            UserRole::Auditor,
+        );
+
         db.data.insert(u1.username().to_string(), u1);
         db.data.insert(u2.username().to_string(), u2);
+        db.data.insert(u3.username().to_string(), u3);
+        db.data.insert(u4.username().to_string(), u4);
+
+        Ok(db)
+    }
+}
+
+/// Run an operation writing or reading a file on the blocking pool. The I/O errors are kept as
+/// such, the other ones are only kept as their message.
+async fn blocking<T, F>(operation: F) -> Result<T, Box<dyn Error>>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Box<dyn Error>> + Send + 'static,
+{
+    let res = task::spawn_blocking(move || operation().map_err(|e| match e.downcast::<io::Error>() {
+        Ok(e) => e as Box<dyn Error + Send + Sync>,
+        Err(e) => e.to_string().into(),
+    })).await?;
+    res.map_err(|e| e as Box<dyn Error>)
+}
+
+/// Generate a random password for a privileged account, returns its hash. The password never
+/// goes through the logs: it is shown in the terminal, or written in a file only readable by
+/// the server user next to the database when the server is not started in a terminal.
+fn initial_password(db_path: &str, username: &str) -> Result<String, Box<dyn Error>> {
+    let password = loop {
+        let password = Zeroizing::new(format!("{}.", Alphanumeric.sample_string(&mut OsRng, 20)));
+        if validate_password(&password) {
+            break password;
+        }
+    };
+
+    if stderr().is_terminal() {
+        eprintln!("Password of the account \"{}\": {}", username, *password);
+        warn!("account \"{}\" created with the password shown in the terminal => please use this account only for setup and remove it in prod", username);
+    } else {
+        let file = format!("{}.{}.password", db_path, username);
+        let mut out = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&file)
+            .map_err(|e| format!("cannot write the password of \"{}\" in \"{}\": {}", username, file, e))?;
+        writeln!(out, "{}", *password)?;
+        warn!("account \"{}\" created with the password written in \"{}\" => please use this account only for setup, then remove it and the file", username, file);
+    }
+    hash_password_now(&password)
+}
+
+/// Write a snapshot in a directory under a new name, returns the name
+fn write_snapshot(dir: &Path, data: &[u8]) -> Result<String, Box<dyn Error>> {
+    let name = format!("db_{}.ron", OffsetDateTime::now_utc().unix_timestamp_nanos());
+    fs::create_dir_all(dir)?;
+
+    // Write to a temporary file first so that a snapshot is never partially written
+    let tmp = dir.join(format!(".{}.tmp", name));
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, dir.join(&name))?;
+
+    info!("database snapshot \"{}\" created", &name);
+    Ok(name)
+}
+
+fn read_snapshot(dir: &Path, name: &str) -> Result<Database, Box<dyn Error>> {
+    if !validate_snapshot_name(name) {
+        Err("Invalid snapshot name")?
+    }
+
+    // Snapshots taken by older versions of the server are upgraded before being checked
+    let (snapshot, _) = migrate(&fs::read(dir.join(name))?)?;
+    snapshot.validate()?;
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "$argon2id$v=19$m=65536,t=3,p=4$saKWfVlIpG7rMgG9fk4LYA$qYyHtS8jrIVQ3w4feR32r4t4G9FTSCV74k5r48+A+ISf0ZB7B1Ut5EWn2/L57uDTfXtqO98rJD/BD5jc+FE9mQ";
+
+    fn backups(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("lab3_backups_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn database(phone: &str) -> Database {
+        let mut user = UserAccount::new("alice".to_string(), HASH.to_string(), "0781234567".to_string(), UserRole::HR);
+        user.set_phone_number(phone.to_string(), "default_hr");
+        Database::new(HashMap::from([(user.username().to_string(), user)]))
+    }
+
+    #[test]
+    fn backup_and_restore() {
+        let dir = backups("round_trip");
+        let name = write_snapshot(&dir, &Ron.serialize(&database("0791234567")).unwrap()).unwrap();
+        assert!(validate_snapshot_name(&name));
+
+        let snapshot = read_snapshot(&dir, &name).unwrap();
+        let alice = snapshot.user("alice").unwrap();
+        assert_eq!(alice.phone_number(), "0791234567");
+        assert_eq!(alice.history().len(), 1);
+        assert!(read_snapshot(&dir, "db_0.ron").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The only test using the global database
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_changes_are_kept() {
+        let path = std::env::temp_dir().join(format!("lab3_db_{}.ron", std::process::id()));
+        fs::write(&path, Ron.serialize(&database("0791234567")).unwrap()).unwrap();
+        assert!(DB.set(Database::open(path.to_str().unwrap()).unwrap()).is_ok());
+
+        let tasks: Vec<_> = (0..8)
+            .map(|i| tokio::spawn(async move {
+                Database::update("alice", move |user| {
+                    user.set_phone_number(format!("079000000{}", i), "default_hr");
+                    Ok::<_, ()>(())
+                }).await.unwrap()
+            }))
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), Some(Ok(())));
+        }
+
+        // The change of the fixture and the 8 concurrent ones
+        assert_eq!(Database::get("alice").unwrap().unwrap().history().len(), 9);
+        assert_eq!(Database::update("bob", |_| Ok::<_, ()>(())).await.unwrap(), None);
+
+        let bob = UserAccount::new("bob".to_string(), HASH.to_string(), "0781234567".to_string(), UserRole::HR);
+        assert!(Database::insert_new(bob.clone()).await.unwrap());
+        assert!(!Database::insert_new(bob).await.unwrap());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_snapshot_is_refused() {
+        let dir = backups("invalid");
+        let name = write_snapshot(&dir, &Ron.serialize(&database("079")).unwrap()).unwrap();
 
-        db
+        assert!(read_snapshot(&dir, &name).unwrap_err().to_string().contains("Invalid phone number"));
+        assert!(read_snapshot(&dir, "../db.ron").is_err());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,42 @@
+//! This file is used to negotiate the protocol version and the capabilities with a new client
+
+use crate::connection::Connection;
+use lab3_protocol::{is_supported, Capability, ClientHello, ServerHello, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use log::{info, warn};
+use std::error::Error;
+use strum::IntoEnumIterator;
+
+/// Wait for the hello of the client and answer it, returns the negotiated capabilities
+///
+/// # Error
+/// If the client speaks an unsupported version of the protocol or the exchange failed.
+pub async fn accept(conn: &mut Connection) -> Result<Vec<Capability>, Box<dyn Error>> {
+    let hello = conn.receive::<ClientHello>().await?;
+
+    if !is_supported(hello.version) {
+        warn!("client rejected: unsupported protocol version {}", hello.version);
+        conn.send(&ServerHello::Rejected { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION }).await?;
+        Err(format!("Unsupported protocol version {}", hello.version))?
+    }
+
+    let capabilities = negotiate(&hello.capabilities);
+    info!("client speaks protocol version {} with capabilities {:?}", hello.version, capabilities);
+    conn.send(&ServerHello::Accepted { version: hello.version, capabilities: capabilities.clone() }).await?;
+    Ok(capabilities)
+}
+
+/// Keep the capabilities supported by both sides, the server supports all of them
+fn negotiate(client: &[Capability]) -> Vec<Capability> {
+    Capability::iter().filter(|c| client.contains(c)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_common_capabilities() {
+        assert_eq!(negotiate(&[Capability::Audit, Capability::History]), vec![Capability::History, Capability::Audit]);
+        assert!(negotiate(&[]).is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+//! This file is used to load the private key of the server, which can be encrypted.
+//!
+//! The key must be in PKCS8 format. An encrypted key (`ENCRYPTED PRIVATE KEY`) is decrypted with
+//! a passphrase read once at startup, from the first available source:
+//!     1. The `KEY_PASSPHRASE` environment variable
+//!     2. The file descriptor given by `KEY_PASSPHRASE_FD`
+//!     3. A prompt, if the server runs in a terminal
+//! The passphrase is kept in memory to decrypt the renewed keys, and erased when dropped.
+
+use crate::tls::read_file;
+use pkcs8::der::pem::LineEnding;
+use pkcs8::der::SecretDocument;
+use pkcs8::EncryptedPrivateKeyInfo;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, IsTerminal};
+use zeroize::Zeroizing;
+
+const PRIVATE_KEY_LABEL: &str = "PRIVATE KEY";
+const ENCRYPTED_KEY_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+
+/// Private key decrypted in memory, erased when dropped
+pub struct PrivateKey(SecretDocument);
+
+impl PrivateKey {
+    /// PKCS8 DER encoding of the key
+    #[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+    pub fn der(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    /// PKCS8 PEM encoding of the key
+    pub fn pem(&self) -> Result<Zeroizing<String>, Box<dyn Error>> {
+        Ok(self.0.to_pem(PRIVATE_KEY_LABEL, LineEnding::LF)?)
+    }
+}
+
+// Read the PEM document of a key file, returns its label and its content
+fn read_key(file: &str) -> Result<(String, SecretDocument), Box<dyn Error>> {
+    let pem = Zeroizing::new(read_file(file, "private key")?);
+    let pem = std::str::from_utf8(&pem).map_err(|_| format!("Private key \"{}\" is not a PEM file", file))?;
+    let (label, document) = SecretDocument::from_pem(pem).map_err(|e| format!("Invalid private key \"{}\": {}", file, e))?;
+    Ok((label.to_string(), document))
+}
+
+/// Load a PKCS8 private key, decrypting it with the passphrase if it is encrypted
+pub fn load_key(file: &str, passphrase: Option<&str>) -> Result<PrivateKey, Box<dyn Error>> {
+    match read_key(file)? {
+        (label, document) if label == PRIVATE_KEY_LABEL => Ok(PrivateKey(document)),
+        (label, document) if label == ENCRYPTED_KEY_LABEL => {
+            let passphrase = passphrase.ok_or_else(|| format!("Private key \"{}\" is encrypted but no passphrase was given", file))?;
+            let info = EncryptedPrivateKeyInfo::try_from(document.as_bytes())
+                .map_err(|e| format!("Invalid encrypted private key \"{}\": {}", file, e))?;
+            let document = info.decrypt(passphrase)
+                .map_err(|_| format!("Cannot decrypt private key \"{}\", the passphrase may be wrong", file))?;
+            Ok(PrivateKey(document))
+        }
+        (label, _) => Err(format!("Private key \"{}\" must be in PKCS8 format, found a PEM \"{}\"", file, label))?,
+    }
+}
+
+/// Read the passphrase of a key file if it is encrypted, `None` if it is not
+pub fn passphrase(file: &str) -> Result<Option<Zeroizing<String>>, Box<dyn Error>> {
+    read_passphrase(file, env::var("KEY_PASSPHRASE").ok().map(Zeroizing::new), env::var("KEY_PASSPHRASE_FD").ok())
+}
+
+// Read the passphrase from the values of `KEY_PASSPHRASE` and `KEY_PASSPHRASE_FD`, or the prompt
+fn read_passphrase(file: &str, variable: Option<Zeroizing<String>>, fd: Option<String>) -> Result<Option<Zeroizing<String>>, Box<dyn Error>> {
+    if read_key(file)?.0 != ENCRYPTED_KEY_LABEL {
+        return Ok(None);
+    }
+
+    if let Some(passphrase) = variable {
+        return Ok(Some(passphrase));
+    }
+    if let Some(fd) = fd {
+        let fd = fd.parse::<u32>().map_err(|_| format!("KEY_PASSPHRASE_FD must be a file descriptor, found \"{}\"", fd))?;
+        let content = Zeroizing::new(fs::read_to_string(format!("/dev/fd/{}", fd))
+            .map_err(|e| format!("Cannot read the passphrase from file descriptor {}: {}", fd, e))?);
+        return Ok(Some(Zeroizing::new(content.trim_end_matches(['\r', '\n']).to_string())));
+    }
+    if io::stdin().is_terminal() {
+        let passphrase = rpassword::prompt_password(format!("Passphrase of \"{}\": ", file))?;
+        return Ok(Some(Zeroizing::new(passphrase)));
+    }
+    Err(format!("Private key \"{}\" is encrypted, set KEY_PASSPHRASE or KEY_PASSPHRASE_FD", file))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pkcs8::pkcs5::pbes2::Parameters;
+    use pkcs8::PrivateKeyInfo;
+    use std::os::fd::AsRawFd;
+
+    const KEY: &str = "tls/private/ec_private_pkcs8";
+
+    /// Encrypt the key of the repository with `Passphrase1.` in a temporary file
+    fn encrypted_key(name: &str) -> String {
+        let key = load_key(KEY, None).unwrap();
+
+        // Same scheme as `openssl pkcs8 -topk8`, the default scrypt is too slow for a test
+        let params = Parameters::pbkdf2_sha256_aes256cbc(2048, &[1; 16], &[2; 16]).unwrap();
+        let encrypted = PrivateKeyInfo::try_from(key.der()).unwrap().encrypt_with_params(params, "Passphrase1.").unwrap();
+        let file = env::temp_dir().join(format!("lab3_encrypted_key_{}_{}.pem", name, std::process::id()));
+        fs::write(&file, encrypted.to_pem(ENCRYPTED_KEY_LABEL, LineEnding::LF).unwrap().as_bytes()).unwrap();
+        file.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn load_encrypted_key() {
+        let file = encrypted_key("load");
+
+        assert_eq!(load_key(&file, Some("Passphrase1.")).unwrap().der(), load_key(KEY, None).unwrap().der());
+        assert!(load_key(&file, Some("wrong")).is_err());
+        assert!(load_key(&file, None).is_err());
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn passphrase_from_variable() {
+        let file = encrypted_key("variable");
+
+        let passphrase = read_passphrase(&file, Some(Zeroizing::new("Passphrase1.".to_string())), None).unwrap().unwrap();
+        assert!(load_key(&file, Some(&passphrase)).is_ok());
+
+        // An unencrypted key does not need a passphrase
+        assert!(read_passphrase(KEY, Some(passphrase), None).unwrap().is_none());
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn passphrase_from_file_descriptor() {
+        let file = encrypted_key("fd");
+        let passphrase_file = env::temp_dir().join(format!("lab3_passphrase_{}", std::process::id()));
+        fs::write(&passphrase_file, "Passphrase1.\n").unwrap();
+        let fd = fs::File::open(&passphrase_file).unwrap();
+
+        let passphrase = read_passphrase(&file, None, Some(fd.as_raw_fd().to_string())).unwrap().unwrap();
+        assert_eq!(passphrase.as_str(), "Passphrase1.");
+        assert!(load_key(&file, Some(&passphrase)).is_ok());
+
+        assert!(read_passphrase(&file, None, Some("stdin".to_string())).unwrap_err().to_string().contains("KEY_PASSPHRASE_FD"));
+        fs::remove_file(file).unwrap();
+        fs::remove_file(passphrase_file).unwrap();
+    }
+}
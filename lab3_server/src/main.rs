@@ -1,5 +1,5 @@
-/// This file is used to configure and start the TLS server.
-/// On new connections, the `handle_client` function is called in a thread
+//! This file is used to configure and start the TLS server.
+//! On new connections, the `handle_client` function is called in a Tokio task
 
 mod action;
 mod connection;
@@ -7,25 +7,52 @@ mod database;
 mod user;
 mod validator;
 mod argon2;
+mod audit;
+mod migration;
+mod handshake;
+mod sessions;
+mod registry;
+mod tls;
+mod reload;
+mod key;
+mod config;
 
-use crate::action::{Action, ConnectedUser};
+use crate::action::ConnectedUser;
+use crate::config::{Cli, Command, Config};
+use crate::database::Database;
 use crate::user::UserRole;
+use crate::registry::Registration;
 use connection::Connection;
+use lab3_protocol::{Capability, Expiry, Notice, ProtocolError, Request, RequestMessage, Response, ServerMessage};
 use lazy_static::lazy_static;
-use native_tls::{Identity, Protocol, TlsAcceptor};
 use rand::Rng;
 use std::error::Error;
-use std::fs::File;
-use std::io::Read;
-use std::net::TcpListener;
+use std::net::SocketAddr;
+use std::process;
 use std::sync::Arc;
-use std::thread;
-use log::{error, info};
+use log::{error, info, warn};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Semaphore};
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use crate::tls::{TlsAcceptor, TlsSettings};
+use clap::Parser;
 use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode, ConfigBuilder, format_description};
 
-const SERVER_IP: &str = "localhost:4444";
-const KEY_PATH: &str = "./tls/private/ec_private_pkcs8";
-const CERT_PATH: &str = "./tls/public/ec_cert.pem";
+/// Limits applied to the connections
+#[derive(Clone, Copy)]
+struct Limits {
+    /// Number of clients served at the same time
+    max_connections: usize,
+    /// Time allowed for the TLS handshake and the hello
+    read_timeout: Duration,
+    /// Time allowed between two requests
+    idle_timeout: Duration,
+    /// Maximum duration of a session since the login
+    session_lifetime: Duration,
+    /// Time given to the connections to finish their request when the server stops
+    drain_timeout: Duration,
+}
 
 lazy_static! {
     static ref MOTIVATIONAL_QUOTES: Vec<&'static str> = vec![
@@ -39,10 +66,42 @@ lazy_static! {
     ];
 }
 
-// Handles client connection by sending a banner and then waiting for a client action
-fn handle_client(conn: Connection) -> Result<(), Box<dyn Error>> {
-    let mut u = ConnectedUser::anonymous(conn); // Anonymous user at first
+// The sessions of a user can be revoked from another connection
+fn logout_if_revoked(u: &mut ConnectedUser) {
+    if !u.is_anonymous() && !u.is_session_open() {
+        info!("session of \"{}\" was revoked", u.username());
+        u.logout();
+    }
+}
+
+// Handles client connection by negotiating the protocol, then sending a banner and waiting for
+// a client request
+async fn handle_client(mut conn: Connection, peer: SocketAddr, names: Vec<String>, limits: Limits, mut shutdown: watch::Receiver<bool>) -> Result<(), Box<dyn Error>> {
+    let registration = registry::register(peer);
+    // A client which did not say hello yet is dropped when the server stops
+    let capabilities = tokio::select! {
+        hello = timeout(limits.read_timeout, handshake::accept(&mut conn)) => match hello {
+            Ok(capabilities) => capabilities?,
+            Err(_) => Err("No hello received in time")?,
+        },
+        _ = shutdown.changed() => Err("Server shutting down before the hello")?,
+    };
+    let mut u = ConnectedUser::anonymous(conn, capabilities, limits.session_lifetime); // Anonymous user at first
+    // A client with a certificate is logged in as the user it names
+    action::certificate_login(&mut u, &names)?;
+    let served = serve_requests(&mut u, &registration, limits, &mut shutdown).await;
+    // The session is only kept for a client which lost its connection and can resume it
+    action::leave(&mut u, served.is_ok());
+    served?;
+    Err("Client disconnected")?
+}
+
+// Sends a banner and performs the requests of the client until it exits
+async fn serve_requests(u: &mut ConnectedUser, registration: &Registration, limits: Limits, shutdown: &mut watch::Receiver<bool>) -> Result<(), Box<dyn Error>> {
     loop {
+        logout_if_revoked(u);
+        registration.set_username(u.actor());
+
         let mut banner = "Welcome to RESIGN (hR onlinE uSer dIrectory manaGemeNt)!".to_string();
         if !u.is_anonymous() {
             banner.push_str(
@@ -56,76 +115,237 @@ fn handle_client(conn: Connection) -> Result<(), Box<dyn Error>> {
             }
         }
 
-        // We send the banner to  the client and we expect to receive an Action
-        u.conn().send(&banner)?;
-        let action = u.conn().receive::<Action>()?;
-        action.perform(&mut u)?;
-    }
-}
+        // We send the banner to  the client and we expect to receive a request
+        u.conn().send(&ServerMessage::Banner(banner)).await?;
 
-// Load the server certificate and private key from PKCS8 format
-fn load_server_identity(cert_file: &str, key_file: &str) -> Identity {
-    let mut cert = Vec::new();
-    let mut key = Vec::new();
+        // The session expires when idle for too long or at the end of its lifetime
+        let idle_deadline = Instant::now() + limits.idle_timeout;
+        let (deadline, expiry) = match u.expires_at() {
+            Some(t) if t < idle_deadline => (t, Expiry::Lifetime),
+            _ => (idle_deadline, Expiry::Idle),
+        };
 
-    let mut cert_file = File::open(cert_file).expect("Certificate file not found");
-    let mut key_file = File::open(key_file).expect("Key file not found");
+        // A request being performed is not interrupted by the shutdown, only the waiting
+        let received = tokio::select! {
+            received = timeout_at(deadline, u.conn().receive::<RequestMessage>()) => match received {
+                Ok(message) => Ok(message?),
+                Err(_) => Err(Notice::SessionExpired(expiry)),
+            },
+            _ = shutdown.changed() => Err(Notice::ShuttingDown),
+            _ = registration.terminated() => Err(Notice::Terminated),
+        };
 
-    cert_file.read_to_end(&mut cert).unwrap();
-    key_file.read_to_end(&mut key).unwrap();
+        let message = match received {
+            Ok(message) => message,
+            Err(notice) => {
+                match notice {
+                    Notice::SessionExpired(expiry) => action::expire(u, expiry),
+                    Notice::Terminated => u.logout(),
+                    Notice::ShuttingDown => {}
+                }
+                // The client may already be gone, the connection is closed anyway
+                if u.supports(Capability::Notices) {
+                    let _ = u.conn().send(&ServerMessage::Notice(notice.clone())).await;
+                }
+                match notice {
+                    Notice::SessionExpired(_) => Err("Session expired")?,
+                    Notice::ShuttingDown => Err("Server shutting down")?,
+                    Notice::Terminated => Err("Session terminated")?,
+                }
+            }
+        };
+        if message.request == Request::Exit {
+            return Ok(());
+        }
+        registration.touch();
 
-    Identity::from_pkcs8(&cert, &key).unwrap()
-}
+        // The session may have been revoked while waiting for the request
+        logout_if_revoked(u);
 
-// Create a new TLS configuration
-fn tls_config(cert_file: &str, key_file: &str) -> Arc<TlsAcceptor> {
-    let identity = load_server_identity(cert_file, key_file);
+        // An internal error is only detailed in the logs, the client is told that it failed
+        let result = action::perform(message.request, u).await.unwrap_or_else(|e| {
+            error!("Internal error while performing a request: {}", e);
+            Err(ProtocolError::Internal)
+        });
 
-    let acceptor = TlsAcceptor::builder(identity)
-        .min_protocol_version(Some(Protocol::Tlsv12))
-        .max_protocol_version(Some(Protocol::Tlsv12))
-        .build()
-        .expect("Could not build TlsAcceptor");
+        // The response carries the id of the request it answers
+        u.conn().send(&ServerMessage::Response(Response { id: message.id, result })).await?;
+    }
+}
 
-    Arc::new(acceptor)
+impl Limits {
+    fn new(config: &Config) -> Limits {
+        let limits = &config.limits;
+        Limits {
+            max_connections: limits.max_connections as usize,
+            read_timeout: Duration::from_secs(limits.read_timeout),
+            idle_timeout: Duration::from_secs(limits.idle_timeout),
+            session_lifetime: Duration::from_secs(limits.session_lifetime),
+            drain_timeout: Duration::from_secs(limits.drain_timeout),
+        }
+    }
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let config = Config::load(&cli);
 
-    // Set up logger
-    let config = ConfigBuilder::new()
+    // Set up logger, at the configured level once the configuration is valid
+    let level = config.as_ref().ok().and_then(|c| c.log_level().ok()).unwrap_or(LevelFilter::Info);
+    let log_config = ConfigBuilder::new()
         .set_time_format_custom(format_description!("[day].[month].[year] [hour]:[minute]:[second]"))
         .build();
 
     TermLogger::init(
-        LevelFilter::Info,
-        config,
+        level,
+        log_config,
         TerminalMode::Mixed,
         ColorChoice::Auto,
     ).unwrap();
 
-    // Start TLS server and wait for new connections
-    let acceptor = tls_config(CERT_PATH, KEY_PATH);
-    let listener = TcpListener::bind(SERVER_IP).unwrap();
-    info!("Server started");
-
-    // Handles new connection, negotiate TLS and call handle_client
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let acceptor = acceptor.clone();
-                thread::spawn(move || {
-                    // TLS handshake on top of the connection using the TlsAcceptor
-                    let stream = acceptor.accept(stream);
-                    if stream.is_err() {
-                        error!("TLS handshake failed with error: {}", stream.err().unwrap());
-                    } else {
-                        info!("TLS client connection accepted");
-                        if let Err(e) = handle_client(Connection::new(stream.unwrap())) {
-                            info!("Connection closed: {}", e);
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    let limits = Limits::new(&config);
+    let address = config.server.address.clone();
+    config::init(config);
+
+    // Only check the audit trail when started with `verify-audit`
+    if let Some(Command::VerifyAudit) = cli.command {
+        match audit::verify_default() {
+            Ok(count) => {
+                info!("Audit log verified: {} entries", count);
+                process::exit(0);
+            }
+            Err(e) => {
+                error!("Audit log verification failed: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Load and migrate the database and check the audit trail before accepting clients
+    if let Err(e) = argon2::init() {
+        error!("Cannot set up Argon2: {}", e);
+        process::exit(1);
+    }
+    if let Err(e) = Database::init() {
+        error!("Cannot load the database: {}", e);
+        process::exit(1);
+    }
+    if let Err(e) = audit::init() {
+        error!("Cannot open the audit log: {}", e);
+        process::exit(1);
+    }
+
+    // Start TLS server and wait for new connections until the server is asked to stop
+    let settings = match tls_settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let acceptor = match TlsAcceptor::new(&settings) {
+        Ok(acceptor) => Arc::new(acceptor),
+        Err(e) => {
+            error!("Cannot configure TLS: {}", e);
+            process::exit(1);
+        }
+    };
+    // The certificate is reloaded when renewed, the next connections use the new acceptor
+    let (acceptor, acceptor_rx) = watch::channel(acceptor);
+    tokio::spawn(reload::watch(settings.clone(), acceptor));
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Cannot listen on {}: {}", address, e);
+            process::exit(1);
+        }
+    };
+    let connections = Arc::new(Semaphore::new(limits.max_connections));
+    let (shutdown, shutdown_rx) = watch::channel(false);
+    info!("Server started with {:?}, serving up to {} clients at the same time", settings.backend, limits.max_connections);
+
+    tokio::select! {
+        _ = serve(listener, acceptor_rx, connections.clone(), limits, shutdown_rx) => {}
+        signal = shutdown_signal() => info!("{} received, shutting down", signal),
+    }
+
+    // Tell the connections to stop, then wait for the requests in progress
+    let _ = shutdown.send(true);
+    let drained = timeout(limits.drain_timeout, connections.acquire_many(limits.max_connections as u32))
+        .await
+        .is_ok();
+    if !drained {
+        warn!("Connections still active after {} seconds, stopping anyway", limits.drain_timeout.as_secs());
+    }
+
+    let flushed = match Database::flush().and_then(|_| audit::flush()) {
+        Ok(_) => true,
+        Err(e) => {
+            error!("Failed to flush the server state: {}", e);
+            false
+        }
+    };
+
+    info!("Server stopped");
+    process::exit(if drained && flushed { 0 } else { 1 });
+}
+
+// Read the TLS settings, asking the passphrase of the private key if it is encrypted
+fn tls_settings() -> Result<TlsSettings, Box<dyn Error>> {
+    let config = config::get();
+    let tls = &config.tls;
+    Ok(TlsSettings {
+        backend: config.tls_backend()?,
+        cert_file: tls.cert.clone(),
+        key_file: tls.key.clone(),
+        passphrase: key::passphrase(&tls.key)?,
+        client_auth: config.client_auth()?,
+    })
+}
+
+// Accept the connections and serve each client in its own task
+async fn serve(listener: TcpListener, acceptor: watch::Receiver<Arc<TlsAcceptor>>, connections: Arc<Semaphore>, limits: Limits, shutdown: watch::Receiver<bool>) {
+    loop {
+        // Wait for a free slot before accepting, the next clients wait in the listen backlog
+        if connections.available_permits() == 0 {
+            warn!("Maximum number of connections reached, waiting for a client to leave");
+        }
+        let slot = connections.clone().acquire_owned().await.expect("connection limiter closed");
+
+        // Handles new connection, negotiate TLS and call handle_client
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let acceptor = acceptor.borrow().clone();
+                let mut shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    // TLS handshake on top of the connection using the TlsAcceptor, abandoned
+                    // when the server stops so that it does not delay the drain
+                    let handshake = tokio::select! {
+                        handshake = timeout(limits.read_timeout, acceptor.accept(stream)) => handshake,
+                        _ = shutdown.changed() => {
+                            info!("TLS handshake abandoned, the server is shutting down");
                             return;
                         }
+                    };
+                    match handshake {
+                        Ok(Ok((stream, names))) => {
+                            info!("TLS client connection accepted");
+                            if let Err(e) = handle_client(Connection::new(stream), peer, names, limits, shutdown).await {
+                                info!("Connection closed: {}", e);
+                            }
+                        }
+                        Ok(Err(e)) => error!("TLS handshake failed with error: {}", e),
+                        Err(_) => warn!("TLS handshake timed out"),
                     }
+                    drop(slot);
                 });
             }
             Err(e) => {
@@ -134,3 +354,12 @@ fn main() {
         }
     }
 }
+
+// Wait for SIGINT or SIGTERM, returns the name of the signal
+async fn shutdown_signal() -> &'static str {
+    let mut terminate = signal(SignalKind::terminate()).expect("cannot listen to SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
@@ -0,0 +1,171 @@
+//! This file is used to upgrade databases persisted by older versions of the server.
+//!
+//! Every historical format is frozen here so that it can still be read once the current
+//! structs evolve. A file is upgraded one version at a time until it reaches `CURRENT_VERSION`.
+
+use crate::database::Database;
+use crate::user::{UserAccount, UserRole};
+use rustbreak::deser::{DeSerializer, Ron};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use log::info;
+
+/// Version of the format written by this server
+pub const CURRENT_VERSION: u32 = 3;
+
+/// Files written before the versioning was introduced do not have a version field
+const LEGACY_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct VersionProbe {
+    #[serde(default = "legacy_version")]
+    version: u32,
+}
+
+fn legacy_version() -> u32 {
+    LEGACY_VERSION
+}
+
+/// # Version 1
+/// Initial format, without any version field
+#[derive(Serialize, Deserialize)]
+struct DatabaseV1 {
+    data: HashMap<String, UserAccountV1>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserAccountV1 {
+    username: String,
+    password: String,
+    phone_number: String,
+    role: UserRoleV1,
+}
+
+#[derive(Serialize, Deserialize)]
+enum UserRoleV1 {
+    StandardUser,
+    HR,
+    Admin,
+}
+
+impl From<UserRoleV1> for UserRole {
+    fn from(role: UserRoleV1) -> Self {
+        match role {
+            UserRoleV1::StandardUser => UserRole::StandardUser,
+            UserRoleV1::HR => UserRole::HR,
+            UserRoleV1::Admin => UserRole::Admin,
+        }
+    }
+}
+
+/// # Version 2
+/// Add the version field, the records are unchanged
+#[derive(Serialize, Deserialize)]
+struct DatabaseV2 {
+    version: u32,
+    data: HashMap<String, UserAccountV1>,
+}
+
+/// Version 1 -> 2: add the version field
+fn from_v1(db: DatabaseV1) -> DatabaseV2 {
+    info!("migrating database from version 1 to 2");
+    DatabaseV2 {
+        version: 2,
+        data: db.data,
+    }
+}
+
+/// Version 2 -> 3: add an empty change history to every account
+fn from_v2(db: DatabaseV2) -> Database {
+    info!("migrating database from version 2 to 3");
+    Database::new(db.data
+        .into_iter()
+        .map(|(k, u)| (k, UserAccount::new(u.username, u.password, u.phone_number, u.role.into())))
+        .collect())
+}
+
+/// Read a persisted database of any known version and upgrade it to the current version.
+/// Returns the upgraded database and the version it was stored with.
+///
+/// # Error
+/// If the file cannot be parsed or was written by a newer server.
+pub fn migrate(raw: &[u8]) -> Result<(Database, u32), Box<dyn Error>> {
+    let version = DeSerializer::<VersionProbe>::deserialize(&Ron, raw)?.version;
+
+    let db = match version {
+        1 => from_v2(from_v1(Ron.deserialize(raw)?)),
+        2 => from_v2(Ron.deserialize(raw)?),
+        CURRENT_VERSION => Ron.deserialize(raw)?,
+        v if v > CURRENT_VERSION => Err(format!("Database version {} is newer than the supported version {}", v, CURRENT_VERSION))?,
+        v => Err(format!("Unknown database version {}", v))?,
+    };
+
+    Ok((db, version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::Field;
+
+    const FIXTURE_V1: &str = include_str!("../tests/fixtures/db_v1.ron");
+    const FIXTURE_V2: &str = include_str!("../tests/fixtures/db_v2.ron");
+    const FIXTURE_V3: &str = include_str!("../tests/fixtures/db_v3.ron");
+
+    #[test]
+    fn migrate_v1() {
+        let (db, version) = migrate(FIXTURE_V1.as_bytes()).unwrap();
+        assert_eq!(version, 1);
+
+        let hr = db.user("default_hr").unwrap();
+        assert_eq!(hr.phone_number(), "0793175289");
+        assert!(matches!(hr.role(), UserRole::HR));
+        assert!(db.user("default_user").unwrap().history().is_empty());
+    }
+
+    #[test]
+    fn migrate_v2() {
+        let (db, version) = migrate(FIXTURE_V2.as_bytes()).unwrap();
+        assert_eq!(version, 2);
+
+        let admin = db.user("default_admin").unwrap();
+        assert_eq!(admin.phone_number(), "0781234567");
+        assert!(matches!(admin.role(), UserRole::Admin));
+        assert!(admin.history().is_empty());
+    }
+
+    #[test]
+    fn migrate_v3() {
+        let (db, version) = migrate(FIXTURE_V3.as_bytes()).unwrap();
+        assert_eq!(version, 3);
+
+        let user = db.user("default_user").unwrap();
+        assert_eq!(user.phone_number(), "0791234567");
+        assert_eq!(user.history().len(), 2);
+        assert_eq!(user.value_at(Field::PhoneNumber, 0).unwrap(), "0784539872");
+        assert_eq!(user.value_at(Field::PhoneNumber, 1).unwrap(), "0780000000");
+        assert!(user.value_at(Field::PhoneNumber, 3).is_none());
+    }
+
+    #[test]
+    fn migrated_database_round_trips() {
+        let (db, _) = migrate(FIXTURE_V1.as_bytes()).unwrap();
+        let raw = Ron.serialize(&db).unwrap();
+
+        let (db, version) = migrate(&raw).unwrap();
+        assert_eq!(version, CURRENT_VERSION);
+        assert!(db.user("default_hr").is_some());
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        let raw = format!("(version: {}, data: {{}})", CURRENT_VERSION + 1);
+        assert!(migrate(raw.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn reject_garbage() {
+        assert!(migrate(b"not a database").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! This file is used to keep the connections currently served by the server, so that HR can
+//! see who is connected and terminate a connection.
+//!
+//! Each connection registers itself when accepted and is removed from the registry when its
+//! registration is dropped, however the connection ends.
+
+use lab3_protocol::SessionInfo;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<u64, Entry>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+struct Entry {
+    info: SessionInfo,
+    /// Wakes up the connection when it is terminated
+    terminate: Arc<Notify>,
+}
+
+fn connections() -> MutexGuard<'static, HashMap<u64, Entry>> {
+    // A panic while holding the lock cannot leave the map inconsistent
+    CONNECTIONS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Entry of a connection in the registry, removed when dropped
+pub struct Registration {
+    id: u64,
+    terminate: Arc<Notify>,
+}
+
+/// Add a new connection to the registry
+pub fn register(peer: SocketAddr) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let terminate = Arc::new(Notify::new());
+
+    let info = SessionInfo { id, username: None, peer: peer.to_string(), connected_at: now, last_activity: now };
+    connections().insert(id, Entry { info, terminate: terminate.clone() });
+    Registration { id, terminate }
+}
+
+impl Registration {
+    /// Update the user logged in on the connection
+    pub fn set_username(&self, username: Option<&str>) {
+        if let Some(entry) = connections().get_mut(&self.id) {
+            entry.info.username = username.map(str::to_string);
+        }
+    }
+
+    /// Record that a request was received
+    pub fn touch(&self) {
+        if let Some(entry) = connections().get_mut(&self.id) {
+            entry.info.last_activity = OffsetDateTime::now_utc().unix_timestamp();
+        }
+    }
+
+    /// Wait until the connection is terminated, returns immediately if it already was
+    pub async fn terminated(&self) {
+        self.terminate.notified().await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        connections().remove(&self.id);
+    }
+}
+
+/// Connections currently served, in the order they were accepted
+pub fn list() -> Vec<SessionInfo> {
+    let mut sessions: Vec<SessionInfo> = connections().values().map(|e| e.info.clone()).collect();
+    sessions.sort_by_key(|s| s.id);
+    sessions
+}
+
+/// Ask a connection to close, returns the user logged in on it or `None` if it does not exist
+pub fn terminate(id: u64) -> Option<Option<String>> {
+    let connections = connections();
+    let entry = connections.get(&id)?;
+    entry.terminate.notify_one();
+    Some(entry.info.username.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn register_and_terminate() {
+        let registration = register("127.0.0.1:50000".parse().unwrap());
+        registration.set_username(Some("registry_test_user"));
+
+        let info = list().into_iter().find(|s| s.id == registration.id).unwrap();
+        assert_eq!(info.username.as_deref(), Some("registry_test_user"));
+        assert_eq!(info.peer, "127.0.0.1:50000");
+
+        // The termination is kept until the connection waits for it
+        assert_eq!(terminate(registration.id), Some(Some("registry_test_user".to_string())));
+        registration.terminated().await;
+
+        let id = registration.id;
+        drop(registration);
+        assert!(terminate(id).is_none());
+    }
+}
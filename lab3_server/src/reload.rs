@@ -0,0 +1,117 @@
+//! This file is used to reload the certificate and the private key of the server when they are
+//! renewed, without restarting it.
+//!
+//! The files are checked every `RELOAD_INTERVAL`. When their content changed, a new acceptor is
+//! built and used for the next connections, the connections already accepted keep their TLS
+//! session. An invalid renewal is refused and the current certificate is kept. A warning is
+//! logged every day once the certificate expires in less than `EXPIRY_WARNING`.
+
+use crate::tls::{read_file, TlsAcceptor, TlsSettings};
+use log::{error, info, warn};
+use std::error::Error;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::watch;
+use tokio::time::{interval, Duration, Instant};
+use x509_parser::pem::parse_x509_pem;
+
+/// Interval between two checks of the certificate and key files
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time before the expiry of the certificate from which a warning is logged
+const EXPIRY_WARNING: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Interval between two warnings about the expiry of the certificate
+const WARNING_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Content of the certificate and key files
+type Files = (Vec<u8>, Vec<u8>);
+
+fn read_files(settings: &TlsSettings) -> Result<Files, Box<dyn Error>> {
+    Ok((read_file(&settings.cert_file, "certificate")?, read_file(&settings.key_file, "private key")?))
+}
+
+/// Watch the certificate and key files and publish a new acceptor when they change
+pub async fn watch(settings: TlsSettings, acceptor: watch::Sender<Arc<TlsAcceptor>>) {
+    let mut loaded = read_files(&settings).ok();
+    // Content which failed to load, not to report the same invalid renewal every time
+    let mut refused: Option<Files> = None;
+    let mut last_warning: Option<Instant> = None;
+
+    let mut ticks = interval(RELOAD_INTERVAL);
+    loop {
+        ticks.tick().await;
+
+        if let Some((cert, _)) = &loaded {
+            if last_warning.is_none_or(|t| t.elapsed() >= WARNING_INTERVAL) && check_expiry(cert) {
+                last_warning = Some(Instant::now());
+            }
+        }
+
+        // The files may be missing for a moment while they are replaced
+        let files = match read_files(&settings) {
+            Ok(files) => files,
+            Err(_) => continue,
+        };
+        if Some(&files) == loaded.as_ref() || Some(&files) == refused.as_ref() {
+            continue;
+        }
+
+        match TlsAcceptor::new(&settings) {
+            Ok(new) => {
+                info!("Certificate \"{}\" reloaded", settings.cert_file);
+                let _ = acceptor.send(Arc::new(new));
+                loaded = Some(files);
+                refused = None;
+                last_warning = None;
+            }
+            Err(e) => {
+                error!("Invalid certificate renewal, keeping the current certificate: {}", e);
+                refused = Some(files);
+            }
+        }
+    }
+}
+
+/// Expiry of a PEM certificate, as a Unix timestamp
+fn not_after(cert: &[u8]) -> Result<i64, Box<dyn Error>> {
+    let (_, pem) = parse_x509_pem(cert)?;
+    Ok(pem.parse_x509()?.validity().not_after.timestamp())
+}
+
+/// Log a warning if the certificate expired or expires soon, returns true if it did
+fn check_expiry(cert: &[u8]) -> bool {
+    let not_after = match not_after(cert) {
+        Ok(not_after) => not_after,
+        Err(e) => {
+            warn!("Cannot read the expiry date of the certificate: {}", e);
+            return true;
+        }
+    };
+
+    let remaining = not_after - OffsetDateTime::now_utc().unix_timestamp();
+    let date = OffsetDateTime::from_unix_timestamp(not_after).map_or(not_after.to_string(), |d| d.date().to_string());
+    if remaining <= 0 {
+        error!("The server certificate expired on {}", date);
+        true
+    } else if remaining < EXPIRY_WARNING.as_secs() as i64 {
+        warn!("The server certificate expires in {} days, on {}", remaining / (24 * 60 * 60), date);
+        true
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_certificate_is_reported() {
+        let cert = read_file("tls/public/ec_cert.pem", "certificate").unwrap();
+        // Valid until June 29 2022
+        assert_eq!(not_after(&cert).unwrap(), 1656513420);
+        assert!(check_expiry(&cert));
+        assert!(check_expiry(b"not a certificate"));
+    }
+}
@@ -0,0 +1,131 @@
+//! This file is used to keep the sessions opened by a login, so that a client losing its
+//! connection can resume its session with a token instead of logging in again.
+//!
+//! The token is only given to the client, the server keeps its SHA-256 hash as session id.
+//! A token can only be used once: resuming a session gives it a new token.
+//! The sessions are kept in memory and are lost when the server stops.
+
+use lazy_static::lazy_static;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use tokio::time::Instant;
+
+/// Number of random bytes of a token
+const TOKEN_SIZE: usize = 32;
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub username: String,
+    pub expires_at: Instant,
+}
+
+/// Hash of a token used to identify its session
+fn session_id(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn sessions() -> MutexGuard<'static, HashMap<String, Session>> {
+    // A panic while holding the lock cannot leave the map inconsistent
+    SESSIONS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Generate a random token, returns it with its session id
+fn new_token() -> (String, String) {
+    let mut bytes = [0u8; TOKEN_SIZE];
+    OsRng.fill_bytes(&mut bytes);
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let id = session_id(&token);
+    (token, id)
+}
+
+/// Open a session for a user, returns the token to give to the client and the session id
+pub fn open(username: &str, expires_at: Instant) -> (String, String) {
+    let (token, id) = new_token();
+
+    let mut sessions = sessions();
+    let now = Instant::now();
+    sessions.retain(|_, s| s.expires_at > now);
+    sessions.insert(id.clone(), Session { username: username.to_string(), expires_at });
+    (token, id)
+}
+
+/// Find the session of a token if it did not expire and give it a new token, so that the old
+/// one cannot restore the session again. Returns the new token, the new session id and the
+/// session.
+pub fn resume(token: &str) -> Option<(String, String, Session)> {
+    let mut sessions = sessions();
+    let session = sessions.remove(&session_id(token)).filter(|s| s.expires_at > Instant::now())?;
+
+    let (token, id) = new_token();
+    sessions.insert(id.clone(), session.clone());
+    Some((token, id, session))
+}
+
+/// Check that a session was not closed or revoked
+pub fn is_open(id: &str) -> bool {
+    sessions().contains_key(id)
+}
+
+pub fn close(id: &str) {
+    sessions().remove(id);
+}
+
+/// Close every session of a user, returns the number of sessions closed
+pub fn revoke_user(username: &str) -> u64 {
+    let mut sessions = sessions();
+    let before = sessions.len();
+    sessions.retain(|_, s| s.username != username);
+    (before - sessions.len()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    #[test]
+    fn resume_and_revoke() {
+        let expires_at = Instant::now() + Duration::from_secs(60);
+        let (token, id) = open("sessions_test_user", expires_at);
+        let (other, _) = open("sessions_test_user", expires_at);
+
+        let (_, resumed, session) = resume(&token).unwrap();
+        assert_ne!(resumed, id);
+        assert!(!is_open(&id));
+        assert!(is_open(&resumed));
+        assert_eq!(session.username, "sessions_test_user");
+        assert!(resume("not a token").is_none());
+
+        assert_eq!(revoke_user("sessions_test_user"), 2);
+        assert!(!is_open(&resumed));
+        assert!(resume(&other).is_none());
+    }
+
+    #[test]
+    fn token_is_rotated_on_resume() {
+        let (token, _) = open("sessions_test_rotated", Instant::now() + Duration::from_secs(60));
+
+        let (rotated, id, _) = resume(&token).unwrap();
+        assert!(resume(&token).is_none());
+
+        let (_, resumed, session) = resume(&rotated).unwrap();
+        assert_ne!(resumed, id);
+        assert_eq!(session.username, "sessions_test_rotated");
+        assert!(resume(&rotated).is_none());
+        assert_eq!(revoke_user("sessions_test_rotated"), 1);
+    }
+
+    #[test]
+    fn expired_session_cannot_be_resumed() {
+        let (token, id) = open("sessions_test_expired", Instant::now());
+        assert!(resume(&token).is_none());
+        close(&id);
+    }
+}
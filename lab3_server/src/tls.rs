@@ -0,0 +1,214 @@
+//! This file is used to configure the TLS transport of the server.
+//!
+//! Two backends are available: native-tls, which is limited to TLS 1.2, and rustls, which only
+//! negotiates TLS 1.3 with the cipher suites of `CIPHER_SUITES`. The backend is chosen with the
+//! `tls.backend` setting. rustls is the default when the server is built with the `rustls`
+//! feature. Both backends use the same PEM certificate and PKCS8 key.
+//!
+//! With rustls, the server can also ask the clients for a certificate signed by the CA of
+//! `tls.client_ca`. `tls.client_auth` tells if the certificate is `optional` (default) or
+//! `required`.
+
+use crate::connection::Stream;
+use crate::key::{load_key, PrivateKey};
+use native_tls::{Identity, Protocol};
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use tokio::net::TcpStream;
+use zeroize::Zeroizing;
+
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::{version, RootCertStore, ServerConfig, SupportedCipherSuite};
+#[cfg(feature = "rustls")]
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+/// Cipher suites accepted by the rustls backend, in order of preference
+#[cfg(feature = "rustls")]
+pub const CIPHER_SUITES: [SupportedCipherSuite; 3] = [
+    ring::cipher_suite::TLS13_AES_256_GCM_SHA384,
+    ring::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+    ring::cipher_suite::TLS13_AES_128_GCM_SHA256,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// TLS 1.2 through the TLS library of the system
+    NativeTls,
+    /// TLS 1.3 through rustls
+    Rustls,
+}
+
+/// Client certificates accepted by the server
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientAuth {
+    /// PEM file of the CA which signs the client certificates
+    pub ca_file: String,
+    /// Refuse the clients without a certificate
+    pub required: bool,
+}
+
+/// Everything needed to build the acceptor, kept to rebuild it when the files change
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    pub backend: Backend,
+    pub cert_file: String,
+    pub key_file: String,
+    /// Passphrase of the private key, if it is encrypted
+    pub passphrase: Option<Zeroizing<String>>,
+    pub client_auth: Option<ClientAuth>,
+}
+
+pub enum TlsAcceptor {
+    NativeTls(tokio_native_tls::TlsAcceptor),
+    #[cfg(feature = "rustls")]
+    Rustls(tokio_rustls::TlsAcceptor),
+}
+
+impl TlsAcceptor {
+    /// Create the acceptor of a backend with the certificate and the private key of the server
+    pub fn new(settings: &TlsSettings) -> Result<TlsAcceptor, Box<dyn Error>> {
+        let cert_file = settings.cert_file.as_str();
+        let key = load_key(&settings.key_file, settings.passphrase.as_deref().map(String::as_str))?;
+        match settings.backend {
+            Backend::NativeTls if settings.client_auth.is_some() => Err("Client certificates require the rustls backend")?,
+            Backend::NativeTls => Ok(TlsAcceptor::NativeTls(native_tls_config(cert_file, &key)?)),
+            #[cfg(feature = "rustls")]
+            Backend::Rustls => Ok(TlsAcceptor::Rustls(rustls_config(cert_file, &key, settings.client_auth.as_ref())?)),
+            #[cfg(not(feature = "rustls"))]
+            Backend::Rustls => Err("The server was built without the rustls feature")?,
+        }
+    }
+
+    /// Perform the TLS handshake on top of a new connection, returns the encrypted stream and
+    /// the names of the client certificate, which are empty if the client did not send one
+    pub async fn accept(&self, stream: TcpStream) -> Result<(Box<dyn Stream>, Vec<String>), Box<dyn Error + Send + Sync>> {
+        match self {
+            TlsAcceptor::NativeTls(acceptor) => Ok((Box::new(acceptor.accept(stream).await?), Vec::new())),
+            #[cfg(feature = "rustls")]
+            TlsAcceptor::Rustls(acceptor) => {
+                let stream = acceptor.accept(stream).await?;
+                let names = match stream.get_ref().1.peer_certificates() {
+                    Some([cert, ..]) => certificate_names(cert)?,
+                    _ => Vec::new(),
+                };
+                Ok((Box::new(stream), names))
+            }
+        }
+    }
+}
+
+// Read a whole file
+pub fn read_file(file: &str, what: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut content = Vec::new();
+    File::open(file)
+        .and_then(|mut f| f.read_to_end(&mut content))
+        .map_err(|e| format!("Cannot read {} \"{}\": {}", what, file, e))?;
+    Ok(content)
+}
+
+// Load the server certificate and private key from PKCS8 format
+fn load_server_identity(cert_file: &str, key: &PrivateKey) -> Result<Identity, Box<dyn Error>> {
+    let cert = read_file(cert_file, "certificate")?;
+
+    Ok(Identity::from_pkcs8(&cert, key.pem()?.as_bytes()).map_err(|e| format!("Invalid certificate or private key: {}", e))?)
+}
+
+// Create a new TLS configuration, native-tls does not support TLS 1.3 on every platform
+fn native_tls_config(cert_file: &str, key: &PrivateKey) -> Result<tokio_native_tls::TlsAcceptor, Box<dyn Error>> {
+    let identity = load_server_identity(cert_file, key)?;
+
+    let acceptor = native_tls::TlsAcceptor::builder(identity)
+        .min_protocol_version(Some(Protocol::Tlsv12))
+        .max_protocol_version(Some(Protocol::Tlsv12))
+        .build()?;
+
+    Ok(tokio_native_tls::TlsAcceptor::from(acceptor))
+}
+
+/// Cryptographic provider restricted to the allowed cipher suites
+#[cfg(feature = "rustls")]
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(CryptoProvider { cipher_suites: CIPHER_SUITES.to_vec(), ..ring::default_provider() })
+}
+
+// Read the certificates of a PEM file
+#[cfg(feature = "rustls")]
+fn load_certs(file: &str) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
+    let certs = rustls_pemfile::certs(&mut &read_file(file, "certificate")?[..]).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate \"{}\": {}", file, e))?;
+    if certs.is_empty() {
+        Err(format!("No certificate found in \"{}\"", file))?
+    }
+    Ok(certs)
+}
+
+// Create a TLS 1.3 configuration with rustls
+#[cfg(feature = "rustls")]
+fn rustls_config(cert_file: &str, key: &PrivateKey, client_auth: Option<&ClientAuth>) -> Result<tokio_rustls::TlsAcceptor, Box<dyn Error>> {
+    let builder = ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&version::TLS13])?;
+
+    let builder = match client_auth {
+        Some(client_auth) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(&client_auth.ca_file)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider());
+            let verifier = if client_auth.required { verifier } else { verifier.allow_unauthenticated() };
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.der().to_vec()));
+    let config = builder.with_single_cert(load_certs(cert_file)?, key)?;
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Names identifying the owner of a certificate: the DNS names of its subjectAltName, then the
+/// common names of its subject. The e-mail addresses are not usernames, they are ignored.
+#[cfg(feature = "rustls")]
+fn certificate_names(der: &CertificateDer<'_>) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+    let (_, cert) = X509Certificate::from_der(der).map_err(|e| format!("Invalid client certificate: {}", e))?;
+
+    let mut names = Vec::new();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) = name {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.extend(cert.subject().iter_common_name().filter_map(|cn| cn.as_str().ok()).map(str::to_string));
+    Ok(names)
+}
+
+#[cfg(all(test, feature = "rustls"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_tls13_suites_are_allowed() {
+        let provider = crypto_provider();
+        assert_eq!(provider.cipher_suites.len(), CIPHER_SUITES.len());
+        for suite in &provider.cipher_suites {
+            assert!(suite.version() == &version::TLS13, "{:?} is not a TLS 1.3 suite", suite.suite());
+        }
+    }
+
+    #[test]
+    fn names_of_certificate_without_san() {
+        let cert = load_certs("tls/public/ec_cert.pem").unwrap().remove(0);
+        assert_eq!(certificate_names(&cert).unwrap(), vec!["localhost"]);
+    }
+}
@@ -1,13 +1,21 @@
-use std::fmt;
-use std::fmt::{Formatter};
 /// This file is used to store and retrieve user accounts from the database
 ///
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fmt::Formatter;
+use time::OffsetDateTime;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+pub use lab3_protocol::UserInfo;
+
+// The stored types below are independent from the protocol, so that a change of the protocol
+// cannot change the format of the database. They are converted when sent to the clients.
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum UserRole {
     StandardUser,
     HR,
+    Admin,
+    Auditor,
 }
 
 impl fmt::Display for UserRole {
@@ -15,6 +23,55 @@ impl fmt::Display for UserRole {
         match self {
             UserRole::StandardUser => write!(f, "StandardUser"),
             UserRole::HR => write!(f, "HR"),
+            UserRole::Admin => write!(f, "Admin"),
+            UserRole::Auditor => write!(f, "Auditor"),
+        }
+    }
+}
+
+impl From<lab3_protocol::UserRole> for UserRole {
+    fn from(role: lab3_protocol::UserRole) -> Self {
+        match role {
+            lab3_protocol::UserRole::StandardUser => UserRole::StandardUser,
+            lab3_protocol::UserRole::HR => UserRole::HR,
+            lab3_protocol::UserRole::Admin => UserRole::Admin,
+            lab3_protocol::UserRole::Auditor => UserRole::Auditor,
+        }
+    }
+}
+
+/// Fields of a user account that can be edited and are kept in its history
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    PhoneNumber,
+}
+
+impl From<Field> for lab3_protocol::Field {
+    fn from(field: Field) -> Self {
+        match field {
+            Field::PhoneNumber => lab3_protocol::Field::PhoneNumber,
+        }
+    }
+}
+
+/// A single modification of a user account
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Change {
+    pub field: Field,
+    pub old_value: String,
+    pub new_value: String,
+    pub actor: String,
+    pub timestamp: i64,
+}
+
+impl From<Change> for lab3_protocol::Change {
+    fn from(c: Change) -> Self {
+        lab3_protocol::Change {
+            field: c.field.into(),
+            old_value: c.old_value,
+            new_value: c.new_value,
+            actor: c.actor,
+            timestamp: c.timestamp,
         }
     }
 }
@@ -25,15 +82,21 @@ pub struct UserAccount {
     password: String,
     phone_number: String,
     role: UserRole,
+    history: Vec<Change>,
 }
 
 impl UserAccount {
     pub fn new(username: String, password: String, phone_number: String, role: UserRole) -> Self {
+        Self::with_history(username, password, phone_number, role, Vec::new())
+    }
+
+    pub fn with_history(username: String, password: String, phone_number: String, role: UserRole, history: Vec<Change>) -> Self {
         Self {
             username,
             password,
             phone_number,
             role,
+            history,
         }
     }
 
@@ -53,23 +116,45 @@ impl UserAccount {
         &self.role
     }
 
-    pub fn set_phone_number(&mut self, phone_number: String) {
-        self.phone_number = phone_number;
+    /// Append-only list of the modifications, the version N is the account after the N-th change
+    pub fn history(&self) -> &[Change] {
+        &self.history
     }
-}
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct UserInfo {
-    username: String,
-    phone_number: String,
-}
+    /// Change the phone number and record it in the history on behalf of `actor`
+    pub fn set_phone_number(&mut self, phone_number: String, actor: &str) {
+        if phone_number != self.phone_number {
+            let old_value = std::mem::replace(&mut self.phone_number, phone_number);
+            self.record(Field::PhoneNumber, old_value, self.phone_number.clone(), actor);
+        }
+    }
 
-impl UserInfo {
-    pub fn new(username: String, phone_number: String) -> Self {
-        Self {
-            username,
-            phone_number,
+    /// Value of a field as it was at the given version, version 0 being the account creation.
+    /// Returns `None` if the version does not exist.
+    pub fn value_at(&self, field: Field, version: usize) -> Option<String> {
+        if version > self.history.len() {
+            return None;
         }
+
+        let current = match field {
+            Field::PhoneNumber => self.phone_number.clone(),
+        };
+
+        // Undo the changes made after the requested version
+        Some(self.history[version..]
+            .iter()
+            .rev()
+            .filter(|c| c.field == field)
+            .fold(current, |_, c| c.old_value.clone()))
     }
-}
 
+    fn record(&mut self, field: Field, old_value: String, new_value: String, actor: &str) {
+        self.history.push(Change {
+            field,
+            old_value,
+            new_value,
+            actor: actor.to_string(),
+            timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+        });
+    }
+}
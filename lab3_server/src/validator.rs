@@ -1,6 +1,12 @@
 use lazy_static::lazy_static;
 use fancy_regex::Regex;
 
+/// Rules reported to the clients when an input is refused
+pub const PHONE_RULE: &str = "must have the format 0xxxxxxxxx";
+pub const USERNAME_RULE: &str = "must have 1 to 32 letters, digits or underscores";
+pub const SNAPSHOT_RULE: &str = "must be the name of a valid snapshot, ending with .ron";
+pub const PASSWORD_RULE: &str = "must have 8 to 64 characters, with a digit, a lowercase, an uppercase and a special character";
+
 /// Validate swiss phone number in the following format: 0xxxxxxxxx
 pub fn validate_phone(phone: &str) -> bool {
     lazy_static! {
@@ -22,6 +28,18 @@ pub fn validate_username(username: &str) -> bool {
     USERNAME_REGEX.is_match(username).unwrap()
 }
 
+/// Validate a snapshot file name based on the policy:
+/// - only ascii alphanum, underscores, dashes and dots
+/// - must end with `.ron` and must not start with a dot
+/// - max 64 chars
+pub fn validate_snapshot_name(name: &str) -> bool {
+    lazy_static! {
+        static ref SNAPSHOT_REGEX: Regex = Regex::new(r"^(?!\.)[[:alnum:]_\-.]{1,60}\.ron$").unwrap();
+    }
+
+    SNAPSHOT_REGEX.is_match(name).unwrap()
+}
+
 /// Validate a password based on the policy:
 /// - At least **one digit** \[0-9\]
 /// - At least **one lowercase** character \[a-z\]